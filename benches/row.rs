@@ -0,0 +1,65 @@
+#![allow(dead_code, clippy::unused_io_amount)]
+
+#[macro_use]
+#[path = "../src/color.rs"]
+mod color;
+#[path = "../src/canvas.rs"]
+mod canvas;
+#[path = "../src/column_map.rs"]
+mod column_map;
+#[path = "../src/face.rs"]
+mod face;
+#[path = "../src/row.rs"]
+mod row;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use crate::row::Row;
+
+const LENS: [usize; 3] = [10_000, 100_000, 1_000_000];
+
+// Something like a line of minified JSON
+fn line(len: usize, non_ascii: bool) -> String {
+    let item = if non_ascii {
+        "{\"name\":\"café\",\"tags\":[\"\tø\",\"ü\"],\"id\":42},"
+    } else {
+        "{\"name\":\"cafe\",\"tags\":[\"o\",\"u\"],\"id\":42},"
+    };
+    item.repeat(len / item.len())
+}
+
+fn bench_edit(c: &mut Criterion) {
+    for (name, non_ascii, string) in [("edit/ascii", false, "a"), ("edit/non_ascii", true, "é")] {
+        let mut group = c.benchmark_group(name);
+        for len in LENS {
+            let mut row = Row::new(&line(len, non_ascii));
+            let x = row.prev_fit_x(row.last_x() / 2);
+            group.bench_function(BenchmarkId::from_parameter(len), |b| {
+                b.iter(|| {
+                    let next_x = row.insert_str(black_box(x), string);
+                    row.remove_str(x, next_x);
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+fn bench_convert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("convert/non_ascii");
+    for len in LENS {
+        let row = Row::new(&line(len, true));
+        let x = row.prev_fit_x(row.last_x() / 2);
+        let idx = row.x_to_idx(x);
+        group.bench_function(BenchmarkId::new("x_to_idx", len), |b| {
+            b.iter(|| row.x_to_idx(black_box(x)))
+        });
+        group.bench_function(BenchmarkId::new("idx_to_x", len), |b| {
+            b.iter(|| row.idx_to_x(black_box(idx)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_edit, bench_convert);
+criterion_main!(benches);
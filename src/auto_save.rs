@@ -0,0 +1,46 @@
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::util::write_atomic_with_mode;
+
+// The side file holding unsaved changes, e.g. dir/#name#
+pub fn path(file_path: &str) -> PathBuf {
+    let path = Path::new(file_path);
+    let name = path
+        .file_name()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or_default();
+    path.with_file_name(format!("#{}#", name))
+}
+
+// Auto-save files are always in UTF-8 with LF line endings, and no more
+// readable than the file itself
+pub fn write(file_path: &str, text: &str) -> io::Result<()> {
+    let mode = fs::metadata(file_path).map_or(0o600, |m| m.permissions().mode() & 0o777);
+    write_atomic_with_mode(&path(file_path), mode, |writer| {
+        writer.write_all(text.as_bytes())
+    })
+}
+
+pub fn read(file_path: &str) -> io::Result<String> {
+    fs::read_to_string(path(file_path))
+}
+
+pub fn remove(file_path: &str) -> io::Result<()> {
+    match fs::remove_file(path(file_path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Whether an auto-save file is newer than the file itself
+pub fn exists_newer(file_path: &str) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    match (modified(&path(file_path)), modified(Path::new(file_path))) {
+        (Some(auto_saved), Some(saved)) => auto_saved > saved,
+        (Some(_), None) => true,
+        (None, _) => false,
+    }
+}
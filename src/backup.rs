@@ -0,0 +1,90 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BackupMode {
+    Off,
+    // file~
+    Simple,
+    // file.~1~, file.~2~, ...
+    Numbered,
+}
+
+impl BackupMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "off" | "false" | "no" => Some(Self::Off),
+            "simple" | "on" | "true" | "yes" => Some(Self::Simple),
+            "numbered" => Some(Self::Numbered),
+            _ => None,
+        }
+    }
+}
+
+// Copies the file as it is on disk before it gets overwritten
+pub fn create(config: &Config, file_path: &str) -> io::Result<()> {
+    let path = match fs::canonicalize(file_path) {
+        Ok(path) if path.is_file() => path,
+        _ => return Ok(()),
+    };
+    let excluded = config
+        .no_backup_directories
+        .iter()
+        .any(|dir| path.starts_with(dir));
+    if config.backup == BackupMode::Off || excluded {
+        return Ok(());
+    }
+
+    let (dir, name) = match config.backup_directory.as_deref() {
+        // keep backups of files with the same name apart
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            (dir.to_path_buf(), path.to_string_lossy().replace('/', "!"))
+        }
+        None => {
+            let dir = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            (dir, name.to_string())
+        }
+    };
+
+    if config.backup == BackupMode::Simple {
+        fs::copy(&path, dir.join(format!("{}~", name)))?;
+        return Ok(());
+    }
+
+    let mut versions = numbered_versions(&dir, &name)?;
+    let next = versions.last().map_or(1, |&(n, _)| n + 1);
+    fs::copy(&path, dir.join(format!("{}.~{}~", name, next)))?;
+
+    // the new backup counts toward the versions to keep
+    let excess = (versions.len() + 1).saturating_sub(config.backup_versions.max(1));
+    for (_, old) in versions.drain(..excess.min(versions.len())) {
+        fs::remove_file(old)?;
+    }
+    Ok(())
+}
+
+// Returns the existing numbered backups, from the oldest
+fn numbered_versions(dir: &Path, name: &str) -> io::Result<Vec<(usize, PathBuf)>> {
+    let prefix = format!("{}.~", name);
+    let mut versions = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let number = file_name
+            .to_str()
+            .and_then(|s| s.strip_prefix(&prefix))
+            .and_then(|s| s.strip_suffix('~'))
+            .and_then(|s| s.parse().ok());
+        if let Some(number) = number {
+            versions.push((number, entry.path()));
+        }
+    }
+    versions.sort();
+    Ok(versions)
+}
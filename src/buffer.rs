@@ -1,22 +1,49 @@
-extern crate clipboard;
-
-use clipboard::{ClipboardContext, ClipboardProvider};
+use regex::Regex;
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::cmp;
-use std::fs::File;
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
-
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::mem;
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use crate::auto_save;
 use crate::canvas::Canvas;
 use crate::coord::{Pos, Size};
 use crate::edit::{Edit, EditKind};
+use crate::encoding::Encoding;
 use crate::face::{Bg, Fg};
 use crate::key::Key;
+use crate::kill_ring::KillRing;
+use crate::line_ending::LineEnding;
+use crate::link::Link;
 use crate::row::Row;
-use crate::rows::{Rows, RowsMethods};
+use crate::rows::{Rows, RowsBuilder};
 use crate::syntax::Syntax;
-use crate::util::DrawRange;
+use crate::undo_file::UndoFile;
+use crate::undo_tree::UndoTree;
+use crate::util::{write_atomic, DrawRange};
+
+// Rows below the window that are highlighted along with it
+const HIGHLIGHT_MARGIN: usize = 100;
+// Rows highlighted at a time, while waiting for keys or on the way to a
+// far row
+const HIGHLIGHT_BATCH: usize = 2000;
+
+const CORRUPT_HISTORY: &str = "Undo history did not fit the text and was discarded";
 
 pub struct Buffer {
+    pub name: String,
     pub file_path: Option<String>,
+    pub read_only: bool,
+    encoding: Encoding,
+    saved_encoding: Encoding,
+    decode_error: bool,
+    line_ending: LineEnding,
+    saved_line_ending: LineEnding,
     syntax: Box<dyn Syntax>,
     pos: Pos,
     size: Size,
@@ -24,16 +51,27 @@ pub struct Buffer {
     cursor: Pos,
     anchor: Option<Pos>,
     saved_x: usize,
+    // whether long rows are wrapped, where offset.x is the start of the
+    // first screen line instead of a horizontal scroll
+    wrap: bool,
+    // the screen column kept while moving across wrapped lines
+    goal_col: usize,
     rows: Rows,
+    // rows above this one are highlighted, and it has its starting context
+    highlighted: usize,
     draw_range: DrawRange,
-    undo: bool,
-    undo_list: Vec<Edit>,
-    redo_list: Vec<Edit>,
+    undo_tree: UndoTree,
     time: usize,
     saved_time: Option<usize>,
+    auto_saved_time: Option<usize>,
+    recoverable: bool,
+    backed_up: bool,
+    // the modification time and size of the file when last read or written
+    disk_stamp: Option<(SystemTime, u64)>,
     last_key: Option<Key>,
-    clipboard: ClipboardContext,
+    kill_ring: Rc<RefCell<KillRing>>,
     search: Search,
+    links: Vec<Option<Link>>,
 }
 
 #[derive(Default)]
@@ -42,17 +80,71 @@ struct Search {
     index: usize,
     orig_offset: Pos,
     orig_cursor: Pos,
+    replace: Option<Replace>,
+}
+
+struct Replace {
+    regex: Regex,
+    multiline: bool,
+    replacement: String,
+    literal: bool,
+    time: usize,
+    count: usize,
 }
 
+// Both ends are pairs of a row number and a byte index
 struct Match {
+    start: (usize, usize),
+    end: (usize, usize),
+    // the replacement expanded with the captures of the match, when replacing
+    // with a regex
+    replacement: Option<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct View {
     pos: Pos,
-    faces: Vec<(Fg, Bg)>,
+    size: Size,
+    offset: Pos,
+    cursor: Pos,
+    saved_x: usize,
+    draw_range: DrawRange,
+}
+
+impl View {
+    pub fn redraw(&mut self) {
+        self.draw_range.full_expand();
+    }
 }
 
 impl Buffer {
-    pub fn new(file_path: Option<&str>) -> io::Result<Self> {
-        let mut buffer = Self {
+    pub fn new(file_path: Option<&str>, encoding: Option<Encoding>) -> io::Result<Self> {
+        let mut buffer = Self::blank(file_path);
+        buffer.init(encoding)?;
+        Ok(buffer)
+    }
+
+    pub fn listing(name: &str, lines: Vec<(String, Option<Link>)>) -> Self {
+        let mut buffer = Self::blank(None);
+        buffer.name = String::from(name);
+        buffer.read_only = true;
+        let (strings, links): (Vec<_>, _) = lines.into_iter().unzip();
+        buffer.rows = Rows::new(&strings.join("\n"));
+        buffer.links = links;
+        buffer.draw_range.full_expand();
+        buffer
+    }
+
+    fn blank(file_path: Option<&str>) -> Self {
+        Self {
+            name: Self::name_of(file_path),
             file_path: file_path.map(|s| String::from(s)),
+            read_only: false,
+            encoding: Default::default(),
+            saved_encoding: Default::default(),
+            decode_error: false,
+            line_ending: Default::default(),
+            saved_line_ending: Default::default(),
             syntax: <dyn Syntax>::detect(file_path),
             pos: Pos::new(0, 0),
             size: Size::new(0, 0),
@@ -60,43 +152,72 @@ impl Buffer {
             cursor: Pos::new(0, 0),
             anchor: None,
             saved_x: 0,
-            rows: Rows::new(),
+            wrap: false,
+            goal_col: 0,
+            rows: Rows::new(""),
+            highlighted: 0,
             draw_range: Default::default(),
-            undo: false,
-            undo_list: Vec::new(),
-            redo_list: Vec::new(),
+            undo_tree: UndoTree::new(),
             time: 0,
             saved_time: None,
+            auto_saved_time: None,
+            recoverable: false,
+            backed_up: false,
+            disk_stamp: None,
             last_key: None,
-            clipboard: ClipboardProvider::new().unwrap(),
+            kill_ring: Default::default(),
             search: Default::default(),
-        };
-        buffer.init()?;
-        Ok(buffer)
+            links: Vec::new(),
+        }
     }
 
-    fn init(&mut self) -> io::Result<()> {
-        if let Some(file_path) = self.file_path.as_deref() {
-            let file = File::open(file_path)?;
-            let mut reader = BufReader::new(file);
-            let mut buf = String::new();
+    fn name_of(file_path: Option<&str>) -> String {
+        file_path
+            .and_then(|s| Path::new(s).file_name())
+            .and_then(|s| s.to_str())
+            .unwrap_or("newfile")
+            .into()
+    }
 
-            let crlf: &[_] = &['\r', '\n'];
-            let mut ends_with_lf = false;
+    fn init(&mut self, encoding: Option<Encoding>) -> io::Result<()> {
+        let bytes = match self.file_path.as_deref().map(fs::read) {
+            Some(Ok(bytes)) => Some(bytes),
+            Some(Err(e)) if e.kind() == ErrorKind::NotFound => None,
+            Some(Err(e)) => return Err(e),
+            None => None,
+        };
 
-            while reader.read_line(&mut buf)? > 0 {
-                let string = buf.trim_end_matches(crlf);
-                self.rows.push(Row::new(string));
-                ends_with_lf = buf.ends_with('\n');
-                buf.clear();
-            }
-            if self.rows.is_empty() || ends_with_lf {
-                self.rows.push(Row::new(""));
+        if let Some(bytes) = bytes {
+            let (encoding, bom_len) = match encoding {
+                Some(encoding) => encoding.detect_bom(&bytes),
+                None => Encoding::detect(&bytes),
+            };
+            let mut builder = RowsBuilder::default();
+            let decode_error = encoding.decode(&bytes[bom_len..], |chunk| builder.append(chunk));
+            (self.rows, self.line_ending) = builder.finish();
+            self.encoding = encoding;
+            self.saved_encoding = encoding;
+            self.saved_line_ending = self.line_ending;
+            self.decode_error = decode_error;
+            self.read_only = decode_error;
+
+            if let Some(undo_file) = UndoFile::load(self.file_path.as_deref().unwrap(), &bytes) {
+                self.undo_tree = undo_file.undo_tree;
+                self.time = self.undo_tree.next_time();
+                self.saved_time = self.undo_tree.time();
+                self.auto_saved_time = self.saved_time;
             }
         } else {
-            self.rows.push(Row::new(""));
+            if let Some(encoding) = encoding {
+                self.encoding = encoding;
+                self.saved_encoding = encoding;
+            }
         }
-        self.syntax_update(0);
+        if let Some(file_path) = self.file_path.as_deref() {
+            self.recoverable = auto_save::exists_newer(file_path);
+            self.disk_stamp = Self::disk_stamp_of(file_path);
+        }
+        self.highlighted = 0;
         self.draw_range.full_expand();
         Ok(())
     }
@@ -108,48 +229,110 @@ impl Buffer {
         self.draw_range.full_expand();
     }
 
-    pub fn draw(&mut self, canvas: &mut Canvas) -> io::Result<()> {
-        if let Some((start, end)) = self.draw_range.as_tuple() {
+    pub fn view(&self) -> View {
+        View {
+            pos: self.pos,
+            size: self.size,
+            offset: self.offset,
+            cursor: self.cursor,
+            saved_x: self.saved_x,
+            draw_range: self.draw_range.clone(),
+        }
+    }
+
+    pub fn swap_view(&mut self, view: &mut View) {
+        mem::swap(&mut self.pos, &mut view.pos);
+        mem::swap(&mut self.size, &mut view.size);
+        mem::swap(&mut self.offset, &mut view.offset);
+        mem::swap(&mut self.cursor, &mut view.cursor);
+        mem::swap(&mut self.saved_x, &mut view.saved_x);
+        mem::swap(&mut self.draw_range, &mut view.draw_range);
+
+        // the view may be stale if the buffer was edited or wrapped anew
+        // through another window
+        let y = self.cursor.y.min(self.rows.len() - 1);
+        self.cursor = Pos::new(self.rows[y].prev_fit_x(self.cursor.x), y);
+        self.scroll();
+    }
+
+    pub fn needs_draw(&self) -> bool {
+        self.draw_range.as_tuple().is_some()
+    }
+
+    pub fn draw(&mut self, canvas: &mut Canvas, active: bool) -> io::Result<()> {
+        self.highlight_to(self.offset.y + self.size.h + HIGHLIGHT_MARGIN);
+        self.restore_rows(self.offset.y, self.offset.y + self.size.h);
+
+        // the view may have been wrapped at another width
+        if self.wrap && self.offset.y < self.rows.len() {
+            let top = self.line_start(self.offset);
+            if top != self.offset {
+                self.offset = top;
+                self.draw_range.full_expand();
+            }
+        }
+
+        if let Some((start, _)) = self.draw_range.as_tuple().filter(|_| self.wrap) {
+            let overlay = |y| self.match_faces(y);
+            let width = self.wrap_width();
+            self.rows.draw_wrapped(
+                canvas,
+                self.pos,
+                self.offset,
+                self.size,
+                width,
+                start,
+                overlay,
+            )?;
+            self.draw_range.clear();
+        } else if let Some((start, end)) = self.draw_range.as_tuple() {
             let y_range = start.max(self.offset.y)..end.min(self.offset.y + self.size.h);
             let x_range = self.offset.x..(self.offset.x + self.size.w);
+            let pos = Pos::new(self.pos.x, self.pos.y + y_range.start - self.offset.y);
 
-            canvas.set_cursor(self.pos.x, self.pos.y + y_range.start - self.offset.y)?;
-            self.rows.draw(canvas, x_range, y_range)?;
+            let overlay = |y| self.match_faces(y);
+            self.rows.draw(canvas, pos, x_range, y_range, overlay)?;
 
             self.draw_range.clear();
         }
+        self.evict_rows();
 
         canvas.set_cursor(self.pos.x, self.pos.y + self.size.h)?;
-        self.draw_status_bar(canvas)
+        self.draw_status_bar(canvas, active)
     }
 
-    fn draw_status_bar(&self, canvas: &mut Canvas) -> io::Result<()> {
-        let file_path = self.file_path.as_deref().unwrap_or("newfile");
-        let modified = if self.modified() { "+" } else { "" };
+    fn draw_status_bar(&self, canvas: &mut Canvas, active: bool) -> io::Result<()> {
+        let name = &self.name;
+        let modified = if self.read_only {
+            "%"
+        } else if self.modified() {
+            "+"
+        } else {
+            ""
+        };
         let cursor = format!("{}, {}", self.cursor.y + 1, self.cursor.x + 1);
+        let encoding = self.encoding.name();
+        let line_ending = self.line_ending.name();
         let syntax = self.syntax.name();
 
-        let left_len = file_path.len() + modified.len() + 2;
-        let right_len = cursor.len() + syntax.len() + 4;
-        let padding = self.size.w.saturating_sub(left_len + right_len);
+        let left_len = name.len() + modified.len() + 2;
+        let right_len = cursor.len() + encoding.len() + line_ending.len() + syntax.len() + 8;
 
-        canvas.set_fg_color(Fg::Default)?;
+        canvas.set_fg_color(if active { Fg::Default } else { Fg::Comment })?;
         canvas.set_bg_color(Bg::StatusBar)?;
-        canvas.write(b"\x1b[K")?;
 
-        if left_len <= self.size.w {
+        if left_len + right_len <= self.size.w {
             canvas.write(b" ")?;
-            canvas.write(file_path.as_bytes())?;
+            canvas.write(name.as_bytes())?;
             canvas.write(b" ")?;
             canvas.write(modified.as_bytes())?;
-            canvas.write(b"\x1b[K")?;
-        }
-
-        canvas.write_repeat(b" ", padding)?;
-
-        if left_len + right_len <= self.size.w {
+            canvas.write_repeat(b" ", self.size.w - left_len - right_len)?;
             canvas.write(b" ")?;
             canvas.write(cursor.as_bytes())?;
+            canvas.write(b"  ")?;
+            canvas.write(encoding.as_bytes())?;
+            canvas.write(b"  ")?;
+            canvas.write(line_ending.as_bytes())?;
             canvas.write(b" ")?;
             canvas.write(self.syntax.fg_color(canvas.term))?;
             canvas.write(self.syntax.bg_color(canvas.term))?;
@@ -157,12 +340,25 @@ impl Buffer {
             canvas.write(syntax.as_bytes())?;
             canvas.write(b" ")?;
             canvas.reset_color()?;
-            canvas.write(b"\x1b[K")?;
+        } else if left_len <= self.size.w {
+            canvas.write(b" ")?;
+            canvas.write(name.as_bytes())?;
+            canvas.write(b" ")?;
+            canvas.write(modified.as_bytes())?;
+            canvas.write_repeat(b" ", self.size.w - left_len)?;
+        } else {
+            canvas.write_repeat(b" ", self.size.w)?;
         }
         Ok(())
     }
 
     pub fn draw_cursor(&self, canvas: &mut Canvas) -> io::Result<()> {
+        if self.wrap {
+            let line = self.line_start(self.cursor);
+            let x = cmp::min(self.cursor.x - line.x, self.wrap_width());
+            let y = self.count_lines(self.offset, line, self.size.h);
+            return canvas.set_cursor(self.pos.x + x, self.pos.y + y);
+        }
         canvas.set_cursor(
             self.pos.x + self.cursor.x - self.offset.x,
             self.pos.y + self.cursor.y - self.offset.y,
@@ -171,6 +367,10 @@ impl Buffer {
 
     #[allow(clippy::collapsible_else_if)]
     pub fn process_key(&mut self, key: Key) -> &str {
+        if self.read_only && Self::is_edit_key(&key) {
+            return "Buffer is read-only";
+        }
+
         let mut save_key = true;
 
         let message = match key {
@@ -197,11 +397,19 @@ impl Buffer {
                 ""
             }
             Key::ArrowUp | Key::Ctrl(b'P') => {
-                if self.cursor.y > 0 {
-                    let pos = Pos::new(
+                let pos = if self.wrap {
+                    let col = self.goal_col();
+                    let line = self.line_start(self.cursor);
+                    self.prev_line(line).map(|line| self.pos_in_line(line, col))
+                } else if self.cursor.y > 0 {
+                    Some(Pos::new(
                         self.rows[self.cursor.y - 1].prev_fit_x(self.saved_x),
                         self.cursor.y - 1,
-                    );
+                    ))
+                } else {
+                    None
+                };
+                if let Some(pos) = pos {
                     if self.anchor.is_some() {
                         self.highlight_region(pos);
                     }
@@ -211,11 +419,19 @@ impl Buffer {
                 ""
             }
             Key::ArrowDown | Key::Ctrl(b'N') => {
-                if self.cursor.y < self.rows.len() - 1 {
-                    let pos = Pos::new(
+                let pos = if self.wrap {
+                    let col = self.goal_col();
+                    let line = self.line_start(self.cursor);
+                    self.next_line(line).map(|line| self.pos_in_line(line, col))
+                } else if self.cursor.y < self.rows.len() - 1 {
+                    Some(Pos::new(
                         self.rows[self.cursor.y + 1].prev_fit_x(self.saved_x),
                         self.cursor.y + 1,
-                    );
+                    ))
+                } else {
+                    None
+                };
+                if let Some(pos) = pos {
                     if self.anchor.is_some() {
                         self.highlight_region(pos);
                     }
@@ -246,7 +462,9 @@ impl Buffer {
                 ""
             }
             Key::PageUp | Key::Alt(b'v') => {
-                if self.offset.y > 0 {
+                if self.wrap {
+                    self.scroll_page(false);
+                } else if self.offset.y > 0 {
                     let delta = cmp::min(self.size.h, self.offset.y);
                     let pos = Pos::new(
                         self.rows[self.cursor.y - delta].prev_fit_x(self.saved_x),
@@ -262,7 +480,9 @@ impl Buffer {
                 ""
             }
             Key::PageDown | Key::Ctrl(b'V') => {
-                if self.offset.y + self.size.h < self.rows.len() {
+                if self.wrap {
+                    self.scroll_page(true);
+                } else if self.offset.y + self.size.h < self.rows.len() {
                     let delta = cmp::min(self.size.h, self.rows.len() - 1 - self.cursor.y);
                     let pos = Pos::new(
                         self.rows[self.cursor.y + delta].prev_fit_x(self.saved_x),
@@ -331,7 +551,7 @@ impl Buffer {
                         self.indent_region(anchor, unit);
                         self.anchor = None;
                     } else {
-                        let string = unit.repeat(self.rows[self.cursor.y].indent_level);
+                        let string = unit.repeat(self.indent_level(self.cursor.y));
                         if self.rows[self.cursor.y].indent_part() != string {
                             let edit = Edit::indent(self.time(), self.cursor, string);
                             let edit = self.process_edit(edit);
@@ -366,10 +586,9 @@ impl Buffer {
                     self.anchor = None;
                 }
 
-                let time = if let Some(Key::Ctrl(b'J' | b'M')) = self.last_key {
-                    self.undo_list.last().unwrap().time
-                } else {
-                    self.time()
+                let time = match (&self.last_key, self.undo_tree.time()) {
+                    (Some(Key::Ctrl(b'J' | b'M')), Some(time)) => time,
+                    _ => self.time(),
                 };
 
                 let edit = Edit::insert(time, self.cursor, "\n".into(), true);
@@ -386,7 +605,7 @@ impl Buffer {
                         self.push_edit(edit);
                     }
                 } else if let Some(unit) = self.syntax.indent_unit() {
-                    let string = unit.repeat(self.rows[self.cursor.y].indent_level);
+                    let string = unit.repeat(self.indent_level(self.cursor.y));
                     if self.rows[self.cursor.y].indent_part() != string {
                         let edit = Edit::indent(time, self.cursor, string);
                         let edit = self.process_edit(edit);
@@ -396,7 +615,7 @@ impl Buffer {
 
                 self.cursor = cursor2;
                 if let Some(unit) = self.syntax.indent_unit() {
-                    let string = unit.repeat(self.rows[self.cursor.y].indent_level);
+                    let string = unit.repeat(self.indent_level(self.cursor.y));
                     if self.rows[self.cursor.y].indent_part() != string {
                         let edit = Edit::indent(time, self.cursor, string);
                         let edit = self.process_edit(edit);
@@ -411,15 +630,35 @@ impl Buffer {
                     self.unhighlight_region(anchor);
                     self.anchor = None;
                 }
-                let pos = Pos::new(self.rows[self.cursor.y].last_x(), self.cursor.y);
-                self.clipboard
-                    .set_contents(self.rows.read_str(self.cursor, pos))
-                    .unwrap_or(());
+                let pos = if self.cursor.x < self.rows[self.cursor.y].last_x() {
+                    Pos::new(self.rows[self.cursor.y].last_x(), self.cursor.y)
+                } else {
+                    self.rows.next_pos(self.cursor).unwrap_or(self.cursor)
+                };
+                self.kill(self.rows.read_str(self.cursor, pos), false);
                 let edit = Edit::remove(self.time(), self.cursor, pos, false);
                 let edit = self.process_edit(edit);
                 self.push_edit(edit);
                 ""
             }
+            Key::Ctrl(b'L') if self.wrap => {
+                let line = self.line_start(self.cursor);
+                let (center, _) = self.step_lines(line, self.size.h / 2, false);
+                let (bottom, _) = self.step_lines(line, self.size.h - 1, false);
+                self.offset = if let Some(Key::Ctrl(b'L')) = self.last_key {
+                    if self.offset == center {
+                        line
+                    } else if self.offset == line {
+                        bottom
+                    } else {
+                        center
+                    }
+                } else {
+                    center
+                };
+                self.draw_range.full_expand();
+                ""
+            }
             Key::Ctrl(b'L') => {
                 self.offset.y = if let Some(Key::Ctrl(b'L')) = self.last_key {
                     if self.offset.y == self.cursor.y.saturating_sub(self.size.h / 2) {
@@ -441,9 +680,7 @@ impl Buffer {
                     self.anchor = None;
                 }
                 let pos = Pos::new(0, self.cursor.y);
-                self.clipboard
-                    .set_contents(self.rows.read_str(pos, self.cursor))
-                    .unwrap_or(());
+                self.kill(self.rows.read_str(pos, self.cursor), true);
                 let edit = Edit::remove(self.time(), pos, self.cursor, true);
                 let edit = self.process_edit(edit);
                 self.push_edit(edit);
@@ -452,9 +689,8 @@ impl Buffer {
             }
             Key::Ctrl(b'W') => {
                 if let Some(anchor) = self.anchor {
-                    self.clipboard
-                        .set_contents(self.read_region(anchor))
-                        .unwrap_or(());
+                    let string = self.read_region(anchor);
+                    self.kill_ring.borrow_mut().push(string);
                     self.remove_region(anchor);
                     self.anchor = None;
                 }
@@ -465,49 +701,23 @@ impl Buffer {
                     self.remove_region(anchor);
                     self.anchor = None;
                 }
-                let string = self.clipboard.get_contents().unwrap_or("".into());
-                if !string.is_empty() {
-                    let edit = Edit::insert(self.time(), self.cursor, string, true);
-                    let edit = self.process_edit(edit);
-                    self.push_edit(edit);
-                    self.scroll();
-                }
-                ""
-            }
-            Key::Ctrl(b'_') => {
-                if let Some(anchor) = self.anchor {
-                    self.unhighlight_region(anchor);
-                    self.anchor = None;
-                }
-                if !matches!(self.last_key, Some(Key::Ctrl(b'_'))) {
-                    self.undo = !self.undo;
-                }
-                if self.undo {
-                    if let Some(time) = self.undo_list.last().map(|e| e.time) {
-                        while self.undo_list.last().map_or(false, |e| e.time == time) {
-                            let edit = self.undo_list.pop().unwrap();
-                            let edit = self.process_edit(edit);
-                            self.redo_list.push(edit);
-                        }
-                        self.scroll_center();
-                        "Undo"
-                    } else {
-                        "No further undo information"
+                let string = self.kill_ring.borrow_mut().yank();
+                match string {
+                    Some(string) => {
+                        let edit = Edit::insert(self.time(), self.cursor, string, true);
+                        let edit = self.process_edit(edit);
+                        self.push_edit(edit);
+                        self.scroll();
+                        ""
                     }
-                } else {
-                    if let Some(time) = self.redo_list.last().map(|e| e.time) {
-                        while self.redo_list.last().map_or(false, |e| e.time == time) {
-                            let edit = self.redo_list.pop().unwrap();
-                            let edit = self.process_edit(edit);
-                            self.undo_list.push(edit);
-                        }
-                        self.scroll_center();
-                        "Redo"
-                    } else {
-                        "No further redo information"
+                    None => {
+                        save_key = false;
+                        "Kill ring is empty"
                     }
                 }
             }
+            Key::Ctrl(b'_') => self.undo(),
+            Key::Alt(b'_') => self.redo(),
             Key::Alt(b'<') => {
                 let pos = Pos::new(0, 0);
                 if self.anchor.is_some() {
@@ -577,14 +787,30 @@ impl Buffer {
             }
             Key::Alt(b'w') => {
                 if let Some(anchor) = self.anchor {
-                    self.clipboard
-                        .set_contents(self.read_region(anchor))
-                        .unwrap_or(());
+                    let string = self.read_region(anchor);
+                    self.kill_ring.borrow_mut().push(string);
                     self.unhighlight_region(anchor);
                     self.anchor = None;
                 }
                 ""
             }
+            Key::Alt(b'y') => {
+                if let Some(Key::Ctrl(b'Y') | Key::Alt(b'y')) = self.last_key {
+                    let string = self.kill_ring.borrow_mut().yank_pop().unwrap();
+                    // replace the previous yank within the same undo step
+                    let yanked = self.undo_tree.pop().unwrap();
+                    let time = yanked.time;
+                    self.process_edit(yanked);
+                    let edit = Edit::insert(time, self.cursor, string, true);
+                    let edit = self.process_edit(edit);
+                    self.undo_tree.push(edit);
+                    self.scroll();
+                    ""
+                } else {
+                    save_key = false;
+                    "Previous command was not a yank"
+                }
+            }
             Key::Char(ch) => {
                 if let Some(anchor) = self.anchor {
                     self.remove_region(anchor);
@@ -608,12 +834,107 @@ impl Buffer {
         message
     }
 
+    fn kill(&mut self, string: String, prepend: bool) {
+        let mut kill_ring = self.kill_ring.borrow_mut();
+        if let Some(Key::Ctrl(b'K' | b'U')) = self.last_key {
+            kill_ring.append(&string, prepend);
+        } else {
+            kill_ring.push(string);
+        }
+    }
+
+    fn is_edit_key(key: &Key) -> bool {
+        matches!(
+            key,
+            Key::Backspace
+                | Key::Delete
+                | Key::Ctrl(b'D' | b'H' | b'I' | b'J' | b'K' | b'M' | b'U' | b'W' | b'Y' | b'_')
+                | Key::Alt(b'd' | b'h' | b'y' | b'_')
+                | Key::Char(_)
+        )
+    }
+
+    // Highlights again the rows affected by an edit, up to those highlighted
+    // so far
     fn syntax_update(&mut self, y: usize) {
-        let len = self.syntax.update_rows(&mut self.rows[y..]);
+        let end = cmp::min(self.highlighted + 1, self.rows.len());
+        let len = if y < end {
+            self.syntax.update_rows(self.rows.iter_mut(y..end))
+        } else {
+            1
+        };
         self.draw_range.expand(y, y + len);
     }
 
+    // Highlights the rows up to the given one if not yet, evicting those out
+    // of sight between batches
+    fn highlight_to(&mut self, y: usize) {
+        while y >= self.highlighted && !self.highlighted_all() {
+            let start = self.highlighted;
+            let end = cmp::min(cmp::min(y + 2, start + HIGHLIGHT_BATCH), self.rows.len());
+            self.syntax.update_rows(self.rows.iter_mut(start..end));
+            // the last row has its faces but not the context of the next one
+            self.highlighted = if end == self.rows.len() { end } else { end - 1 };
+            self.evict_rows();
+        }
+    }
+
+    // Highlights again the rows in the range that were evicted
+    fn restore_rows(&mut self, start: usize, end: usize) {
+        for y in start..cmp::min(end, self.rows.len()) {
+            if let Some(rows) = self.rows.restore(y) {
+                self.syntax.update_rows(rows);
+            }
+        }
+    }
+
+    // Drops the rows far out of sight, which can be built and highlighted
+    // again from their contexts. Rows with faces of the region are kept.
+    fn evict_rows(&mut self) {
+        let top = self.offset.y.saturating_sub(HIGHLIGHT_MARGIN);
+        let window = top..(self.offset.y + self.size.h + HIGHLIGHT_MARGIN);
+        let region = self.anchor.map_or(0..0, |anchor| {
+            self.cursor.min(anchor).y..(self.cursor.max(anchor).y + 1)
+        });
+        self.rows.evict(&[window, region]);
+    }
+
+    pub fn highlighted_all(&self) -> bool {
+        self.highlighted == self.rows.len()
+    }
+
+    // Highlights some more rows in idle time, which are out of sight
+    pub fn highlight_more(&mut self) {
+        self.highlight_to(self.highlighted + HIGHLIGHT_BATCH);
+    }
+
+    fn indent_level(&mut self, y: usize) -> usize {
+        self.highlight_to(y);
+        self.restore_rows(y, y + 1);
+        self.rows[y].indent_level
+    }
+
     fn scroll(&mut self) {
+        if self.wrap {
+            let line = self.line_start(self.cursor);
+            let top = if self.offset.y < self.rows.len() {
+                self.line_start(self.offset)
+            } else {
+                line
+            };
+            let top = if line < top {
+                line
+            } else if self.count_lines(top, line, self.size.h) == self.size.h {
+                self.step_lines(line, self.size.h - 1, false).0
+            } else {
+                top
+            };
+            if top != self.offset {
+                self.offset = top;
+                self.draw_range.full_expand();
+            }
+            return;
+        }
         if self.cursor.x < self.offset.x {
             self.offset.x = self.cursor.x;
             self.draw_range.full_expand();
@@ -633,6 +954,15 @@ impl Buffer {
     }
 
     fn scroll_center(&mut self) {
+        if self.wrap {
+            let line = self.line_start(self.cursor);
+            if line < self.offset || self.count_lines(self.offset, line, self.size.h) == self.size.h
+            {
+                self.offset = self.step_lines(line, self.size.h / 2, false).0;
+                self.draw_range.full_expand();
+            }
+            return;
+        }
         if self.cursor.x < self.offset.x || self.cursor.x >= self.offset.x + self.size.w {
             self.offset.x = self.cursor.x.saturating_sub(self.size.w / 2);
             self.draw_range.full_expand();
@@ -644,9 +974,127 @@ impl Buffer {
     }
 }
 
+// Screen lines of wrapped rows are identified by their starts
+impl Buffer {
+    pub fn toggle_wrap(&mut self) -> bool {
+        self.wrap = !self.wrap;
+        self.offset.x = 0;
+        self.scroll();
+        self.draw_range.full_expand();
+        self.wrap
+    }
+
+    // The last column is left for the continuation indicator
+    fn wrap_width(&self) -> usize {
+        self.size.w.saturating_sub(1).max(1)
+    }
+
+    fn line_start(&self, pos: Pos) -> Pos {
+        let starts = self.rows[pos.y].wrap(self.wrap_width());
+        let i = starts.partition_point(|&x| x <= pos.x);
+        Pos::new(starts[i - 1], pos.y)
+    }
+
+    fn prev_line(&self, line: Pos) -> Option<Pos> {
+        if line.x > 0 {
+            Some(self.line_start(Pos::new(line.x - 1, line.y)))
+        } else if line.y > 0 {
+            let starts = self.rows[line.y - 1].wrap(self.wrap_width());
+            Some(Pos::new(starts[starts.len() - 1], line.y - 1))
+        } else {
+            None
+        }
+    }
+
+    fn next_line(&self, line: Pos) -> Option<Pos> {
+        let starts = self.rows[line.y].wrap(self.wrap_width());
+        let i = starts.partition_point(|&x| x <= line.x);
+        if let Some(&x) = starts.get(i) {
+            Some(Pos::new(x, line.y))
+        } else if line.y < self.rows.len() - 1 {
+            Some(Pos::new(0, line.y + 1))
+        } else {
+            None
+        }
+    }
+
+    // Moves up to n screen lines, returning the line reached and the count
+    fn step_lines(&self, mut line: Pos, n: usize, forward: bool) -> (Pos, usize) {
+        for i in 0..n {
+            let next = if forward {
+                self.next_line(line)
+            } else {
+                self.prev_line(line)
+            };
+            match next {
+                Some(next) => line = next,
+                None => return (line, i),
+            }
+        }
+        (line, n)
+    }
+
+    // Counts screen lines from one line to a later one, up to the limit
+    fn count_lines(&self, mut line: Pos, to: Pos, limit: usize) -> usize {
+        let mut n = 0;
+        while line < to && n < limit {
+            match self.next_line(line) {
+                Some(next) => line = next,
+                None => break,
+            }
+            n += 1;
+        }
+        n
+    }
+
+    // The position at the screen column in a line, or at its end
+    fn pos_in_line(&self, line: Pos, col: usize) -> Pos {
+        let row = &self.rows[line.y];
+        let end = match self.next_line(line) {
+            Some(next) if next.y == line.y => row.prev_x(next.x).unwrap(),
+            _ => row.last_x(),
+        };
+        Pos::new(row.prev_fit_x(cmp::min(line.x + col, end)), line.y)
+    }
+
+    fn goal_col(&mut self) -> usize {
+        match self.last_key {
+            Some(
+                Key::ArrowUp
+                | Key::ArrowDown
+                | Key::PageUp
+                | Key::PageDown
+                | Key::Ctrl(b'P' | b'N' | b'V')
+                | Key::Alt(b'v'),
+            ) => (),
+            _ => self.goal_col = self.cursor.x - self.line_start(self.cursor).x,
+        }
+        self.goal_col
+    }
+
+    // Scrolls a page of screen lines, moving the cursor as many lines
+    fn scroll_page(&mut self, forward: bool) {
+        let (top, n) = self.step_lines(self.offset, self.size.h, forward);
+        if n == 0 || forward && n < self.size.h {
+            return;
+        }
+        let col = self.goal_col();
+        let line = self.line_start(self.cursor);
+        let pos = self.pos_in_line(self.step_lines(line, n, forward).0, col);
+        if self.anchor.is_some() {
+            self.highlight_region(pos);
+        }
+        self.cursor = pos;
+        self.offset = top;
+        self.draw_range.full_expand();
+    }
+}
+
 impl Buffer {
     pub fn modified(&self) -> bool {
-        self.saved_time != self.undo_list.last().map(|e| e.time)
+        self.saved_time != self.undo_tree.time()
+            || self.saved_encoding != self.encoding
+            || self.saved_line_ending != self.line_ending
     }
 
     fn time(&mut self) -> usize {
@@ -659,6 +1107,9 @@ impl Buffer {
         let kind = match edit.kind {
             EditKind::Insert(pos1, string, mv) => {
                 let pos2 = self.rows.insert_str(pos1, &string);
+                if pos1.y < self.highlighted {
+                    self.highlighted += pos2.y - pos1.y;
+                }
                 self.cursor = if mv { pos2 } else { pos1 };
                 self.saved_x = (if mv { pos2 } else { pos1 }).x;
                 self.syntax_update(pos1.y);
@@ -669,6 +1120,11 @@ impl Buffer {
             }
             EditKind::Remove(pos1, pos2, mv) => {
                 let string = self.rows.remove_str(pos1, pos2);
+                if pos2.y < self.highlighted {
+                    self.highlighted -= pos2.y - pos1.y;
+                } else if pos1.y < self.highlighted {
+                    self.highlighted = pos1.y;
+                }
                 self.cursor = pos1;
                 self.saved_x = pos1.x;
                 self.syntax_update(pos1.y);
@@ -679,7 +1135,7 @@ impl Buffer {
             }
             EditKind::Indent(pos, string) => {
                 let width1 = self.rows[pos.y].indent_width();
-                let string = self.rows[pos.y].indent(&string);
+                let string = self.rows.indent(pos.y, &string);
                 let width2 = self.rows[pos.y].indent_width();
                 let x = if width1 < width2 {
                     pos.x.saturating_add(width2 - width1).max(width2)
@@ -701,15 +1157,40 @@ impl Buffer {
     }
 
     fn push_edit(&mut self, edit: Edit) {
-        self.undo_list.push(edit);
-        self.redo_list.clear();
-        self.undo = false;
+        self.undo_tree.push(edit);
     }
 
     fn merge_edit(&mut self, edit: Edit) {
-        let last_edit = self.undo_list.pop().unwrap();
-        let edit = edit.merge(last_edit);
-        self.undo_list.push(edit);
+        match self.undo_tree.pop() {
+            Some(last_edit) => self.undo_tree.push(edit.merge(last_edit)),
+            None => self.push_edit(edit),
+        }
+    }
+
+    // Processes the edits of an undo tree node from the last one. Edits
+    // loaded from an undo file may not fit the text, in which case the
+    // history is discarded and false is returned.
+    fn process_step(&mut self, index: usize, edits: Vec<Edit>, modified: bool) -> bool {
+        let mut processed = Vec::new();
+        for edit in edits.into_iter().rev() {
+            if !self.fits(&edit) {
+                self.undo_tree = UndoTree::new();
+                self.saved_time = (modified || !processed.is_empty()).then(|| self.time());
+                return false;
+            }
+            processed.push(self.process_edit(edit));
+        }
+        self.undo_tree.restore(index, processed);
+        true
+    }
+
+    fn fits(&self, edit: &Edit) -> bool {
+        let fits =
+            |pos: &Pos| pos.y < self.rows.len() && self.rows[pos.y].prev_fit_x(pos.x) == pos.x;
+        match &edit.kind {
+            EditKind::Insert(pos, _, _) | EditKind::Indent(pos, _) => fits(pos),
+            EditKind::Remove(pos1, pos2, _) => fits(pos1) && fits(pos2) && pos1 <= pos2,
+        }
     }
 }
 
@@ -723,6 +1204,8 @@ impl Buffer {
     fn highlight_region(&mut self, pos: Pos) {
         let pos1 = self.cursor.min(pos);
         let pos2 = self.cursor.max(pos);
+        self.highlight_to(pos2.y);
+        self.restore_rows(pos1.y, pos2.y + 1);
 
         for y in pos1.y..=pos2.y {
             let row = &mut self.rows[y];
@@ -748,6 +1231,8 @@ impl Buffer {
     fn unhighlight_region(&mut self, anchor: Pos) {
         let pos1 = self.cursor.min(anchor);
         let pos2 = self.cursor.max(anchor);
+        self.highlight_to(pos2.y);
+        self.restore_rows(pos1.y, pos2.y + 1);
 
         for y in pos1.y..=pos2.y {
             let row = &mut self.rows[y];
@@ -770,7 +1255,7 @@ impl Buffer {
         let time = self.time();
 
         for y in pos1.y..=pos2.y {
-            let string = unit.repeat(self.rows[y].indent_level);
+            let string = unit.repeat(self.indent_level(y));
             if self.rows[y].is_whitespace() {
                 if !self.rows[y].is_empty() {
                     let edit = Edit::indent(time, Pos::new(0, y), "".into());
@@ -800,36 +1285,73 @@ impl Buffer {
 }
 
 impl Buffer {
-    pub fn search(&mut self, query: &str, backward: bool) {
-        for (y, row) in self.rows.iter_mut().enumerate() {
-            for (idx, _) in row.string.match_indices(query) {
-                let pos = Pos::new(row.idx_to_x(idx), y);
-                let mut faces = vec![(Fg::Match, Bg::Match); query.len()];
-                row.faces[idx..(idx + query.len())].swap_with_slice(&mut faces);
-                self.search.matches.push(Match { pos, faces });
+    pub fn search(&mut self, regex: &Regex, multiline: bool, backward: bool) {
+        self.find_matches(regex, multiline, None);
+        self.select_match(backward);
+    }
+
+    // Finds all the matches, expanding the replacement if any with the
+    // captures of each, which are taken in the context of the whole text or
+    // line as the match itself is
+    fn find_matches(&mut self, regex: &Regex, multiline: bool, replacement: Option<&str>) {
+        let expand = |haystack: &str, idx: usize| {
+            replacement.map(|replacement| {
+                let mut string = String::new();
+                let caps = regex.captures_at(haystack, idx).unwrap();
+                caps.expand(replacement, &mut string);
+                string
+            })
+        };
+
+        if multiline {
+            let text = self.rows.text("\n");
+            for m in regex.find_iter(&text).filter(|m| !m.is_empty()) {
+                self.search.matches.push(Match {
+                    start: self.rows.locate(m.start()),
+                    end: self.rows.locate(m.end()),
+                    replacement: expand(&text, m.start()),
+                });
+            }
+        } else {
+            for y in 0..self.rows.len() {
+                let line = self.rows.line(y);
+                for m in regex.find_iter(&line).filter(|m| !m.is_empty()) {
+                    self.search.matches.push(Match {
+                        start: (y, m.start()),
+                        end: (y, m.end()),
+                        replacement: expand(&line, m.start()),
+                    });
+                }
             }
         }
+    }
+
+    // Moves to the first match after the cursor, or the last one before it
+    fn select_match(&mut self, backward: bool) {
         if self.search.matches.is_empty() {
             return;
         }
+        let cursor = (
+            self.cursor.y,
+            self.rows[self.cursor.y].x_to_idx(self.cursor.x),
+        );
         self.search.index = if backward {
             self.search
                 .matches
                 .iter()
-                .rposition(|m| m.pos < self.cursor)
+                .rposition(|m| m.start < cursor)
                 .unwrap_or(self.search.matches.len() - 1)
         } else {
             self.search
                 .matches
                 .iter()
-                .position(|m| m.pos >= self.cursor)
+                .position(|m| m.start >= cursor)
                 .unwrap_or(0)
         };
         self.search.orig_offset = self.offset;
         self.search.orig_cursor = self.cursor;
 
         self.move_to_match();
-        self.highlight_match(true);
         self.draw_range.full_expand();
     }
 
@@ -839,8 +1361,6 @@ impl Buffer {
             return;
         }
 
-        self.highlight_match(false);
-
         self.search.index = if backward {
             if self.search.index > 0 {
                 self.search.index - 1
@@ -856,7 +1376,6 @@ impl Buffer {
         };
 
         self.move_to_match();
-        self.highlight_match(true);
         self.draw_range.full_expand();
     }
 
@@ -864,12 +1383,6 @@ impl Buffer {
         if self.search.matches.is_empty() {
             return;
         }
-
-        for m in self.search.matches.iter_mut() {
-            let row = &mut self.rows[m.pos.y];
-            let idx = row.x_to_idx(m.pos.x);
-            row.faces[idx..(idx + m.faces.len())].swap_with_slice(&mut m.faces);
-        }
         self.search.matches.clear();
 
         if restore {
@@ -885,31 +1398,262 @@ impl Buffer {
     }
 
     fn move_to_match(&mut self) {
-        let m = &self.search.matches[self.search.index];
-        self.cursor = m.pos;
+        self.cursor = self.match_range(self.search.index).0;
         self.scroll_center();
     }
 
-    fn highlight_match(&mut self, current: bool) {
-        let m = &self.search.matches[self.search.index];
-        let row = &mut self.rows[m.pos.y];
-        let idx = row.x_to_idx(m.pos.x);
-        let face = if current {
-            (Fg::CurrentMatch, Bg::CurrentMatch)
-        } else {
-            (Fg::Match, Bg::Match)
+    // Returns the byte ranges and faces of the matches in the row, which are
+    // laid over the faces of the row when drawn
+    fn match_faces(&self, y: usize) -> Vec<(Range<usize>, (Fg, Bg))> {
+        let matches = &self.search.matches;
+        let i = matches.partition_point(|m| m.end.0 < y);
+
+        matches[i..]
+            .iter()
+            .take_while(|m| m.start.0 <= y)
+            .enumerate()
+            .map(|(j, m)| {
+                let idx1 = if m.start.0 == y { m.start.1 } else { 0 };
+                let idx2 = if m.end.0 == y {
+                    m.end.1
+                } else {
+                    self.rows[y].string.len()
+                };
+                let face = if i + j == self.search.index {
+                    (Fg::CurrentMatch, Bg::CurrentMatch)
+                } else {
+                    (Fg::Match, Bg::Match)
+                };
+                (idx1..idx2, face)
+            })
+            .collect()
+    }
+}
+
+impl Buffer {
+    pub fn start_replace(
+        &mut self,
+        regex: Regex,
+        multiline: bool,
+        replacement: String,
+        literal: bool,
+    ) -> bool {
+        if let Some(anchor) = self.anchor {
+            self.unhighlight_region(anchor);
+            self.anchor = None;
+        }
+        // the whole session is undone as one step
+        let time = self.time();
+        self.search.replace = Some(Replace {
+            regex,
+            multiline,
+            replacement,
+            literal,
+            time,
+            count: 0,
+        });
+        self.find_replace_match()
+    }
+
+    // Replaces the current match and moves on to the next one, shifting the
+    // matches after it by the replacement
+    pub fn replace_match(&mut self) -> bool {
+        let index = self.search.index;
+        let (pos1, pos2) = self.match_range(index);
+        let string = self.replacement(index);
+        let m = self.search.matches.remove(index);
+        self.replace_range(pos1, pos2, string);
+        self.search.replace.as_mut().unwrap().count += 1;
+
+        let end = (
+            self.cursor.y,
+            self.rows[self.cursor.y].x_to_idx(self.cursor.x),
+        );
+        let dy = self.cursor.y as isize - m.end.0 as isize;
+        let shift = |(y, idx): (usize, usize)| {
+            if y == m.end.0 {
+                (end.0, end.1 + idx - m.end.1)
+            } else {
+                (y.wrapping_add_signed(dy), idx)
+            }
         };
-        for i in idx..(idx + m.faces.len()) {
-            row.faces[i] = face;
+        for m2 in self.search.matches[index..].iter_mut() {
+            if dy == 0 && m2.start.0 > m.end.0 {
+                break;
+            }
+            m2.start = shift(m2.start);
+            m2.end = shift(m2.end);
+        }
+
+        if index < self.search.matches.len() {
+            self.move_to_match();
+            self.draw_range.full_expand();
+            true
+        } else {
+            self.clear_matches(false);
+            false
+        }
+    }
+
+    pub fn skip_match(&mut self) -> bool {
+        if self.search.index + 1 < self.search.matches.len() {
+            self.next_match(false);
+            true
+        } else {
+            self.clear_matches(false);
+            false
+        }
+    }
+
+    pub fn replace_all(&mut self) {
+        let ranges: Vec<_> = (self.search.index..self.search.matches.len())
+            .map(|i| self.match_range(i))
+            .collect();
+        let strings: Vec<_> = (self.search.index..self.search.matches.len())
+            .map(|i| self.replacement(i))
+            .collect();
+        self.clear_matches(false);
+
+        // replace from the end so that earlier positions stay valid
+        for (&(pos1, pos2), string) in ranges.iter().zip(strings).rev() {
+            self.replace_range(pos1, pos2, string);
+        }
+        self.search.replace.as_mut().unwrap().count += ranges.len();
+        self.scroll();
+    }
+
+    // Returns the number of replaced matches
+    pub fn finish_replace(&mut self) -> usize {
+        self.clear_matches(false);
+        self.search.replace.take().map_or(0, |r| r.count)
+    }
+
+    // Moves to the first match after the cursor, without wrapping around
+    fn find_replace_match(&mut self) -> bool {
+        let replace = self.search.replace.take().unwrap();
+        let replacement = (!replace.literal).then_some(replace.replacement.as_str());
+        self.find_matches(&replace.regex, replace.multiline, replacement);
+        self.select_match(false);
+        self.search.replace = Some(replace);
+
+        let found = !self.search.matches.is_empty()
+            && self.match_range(self.search.index).0 >= self.search.orig_cursor;
+        if !found {
+            self.clear_matches(true);
+        }
+        found
+    }
+
+    fn match_range(&self, index: usize) -> (Pos, Pos) {
+        let Match { start, end, .. } = self.search.matches[index];
+        let pos1 = Pos::new(self.rows[start.0].idx_to_x(start.1), start.0);
+        let pos2 = Pos::new(self.rows[end.0].idx_to_x(end.1), end.0);
+        (pos1, pos2)
+    }
+
+    fn replacement(&self, index: usize) -> String {
+        match &self.search.matches[index].replacement {
+            Some(string) => string.clone(),
+            None => self.search.replace.as_ref().unwrap().replacement.clone(),
+        }
+    }
+
+    fn replace_range(&mut self, pos1: Pos, pos2: Pos, string: String) {
+        let time = self.search.replace.as_ref().unwrap().time;
+        let edit = Edit::remove(time, pos1, pos2, true);
+        let edit = self.process_edit(edit);
+        self.push_edit(edit);
+        if !string.is_empty() {
+            let edit = Edit::insert(time, pos1, string, true);
+            let edit = self.process_edit(edit);
+            self.push_edit(edit);
         }
     }
 }
 
 impl Buffer {
     pub fn goto_line(&mut self, num: usize) {
-        let y = num.saturating_sub(1);
-        let y = y.min(self.rows.last_pos().y);
-        let pos = Pos::new(0, y);
+        self.goto(Pos::new(0, num.saturating_sub(1)));
+    }
+
+    pub fn undo(&mut self) -> &'static str {
+        if let Some(anchor) = self.anchor {
+            self.unhighlight_region(anchor);
+            self.anchor = None;
+        }
+        self.last_key = None;
+        let modified = self.modified();
+        match self.undo_tree.undo() {
+            Some((index, edits)) => {
+                let message = if self.process_step(index, edits, modified) {
+                    "Undo"
+                } else {
+                    CORRUPT_HISTORY
+                };
+                self.scroll_center();
+                message
+            }
+            None => "No further undo information",
+        }
+    }
+
+    pub fn redo(&mut self) -> &'static str {
+        if let Some(anchor) = self.anchor {
+            self.unhighlight_region(anchor);
+            self.anchor = None;
+        }
+        self.last_key = None;
+        let modified = self.modified();
+        match self.undo_tree.redo() {
+            Some((index, edits)) => {
+                let message = if self.process_step(index, edits, modified) {
+                    "Redo"
+                } else {
+                    CORRUPT_HISTORY
+                };
+                self.scroll_center();
+                message
+            }
+            None => "No further redo information",
+        }
+    }
+
+    pub fn switch_undo_branch(&mut self) -> String {
+        self.last_key = None;
+        match self.undo_tree.switch_branch() {
+            Some((n, len)) => format!("Redo will follow branch {}/{}", n, len),
+            None => "Not at an undo branch point".into(),
+        }
+    }
+
+    pub fn undo_tree_lines(&self) -> Vec<(String, usize)> {
+        self.undo_tree.render()
+    }
+
+    pub fn undo_state(&self) -> usize {
+        self.undo_tree.current()
+    }
+
+    // Undoes to the common ancestor, then redoes down to the given state
+    pub fn goto_undo_state(&mut self, index: usize) {
+        let (undos, redos) = self.undo_tree.path_to(index);
+        for _ in 0..undos {
+            self.undo();
+        }
+        for index in redos {
+            // the history may have been discarded on the way
+            if index >= self.undo_tree.nodes().len() {
+                break;
+            }
+            self.undo_tree.select(index);
+            self.redo();
+        }
+        self.last_key = None;
+    }
+
+    pub fn goto(&mut self, pos: Pos) {
+        let y = pos.y.min(self.rows.last_pos().y);
+        let pos = Pos::new(self.rows[y].prev_fit_x(pos.x), y);
         if self.anchor.is_some() {
             self.highlight_region(pos);
         }
@@ -918,6 +1662,79 @@ impl Buffer {
         self.scroll_center();
     }
 
+    // Returns the position of the first match in each matching line
+    pub fn matching_lines(&self, regex: &Regex) -> Vec<(Pos, Cow<'_, str>)> {
+        (0..self.rows.len())
+            .filter_map(|y| {
+                let line = self.rows.line(y);
+                let m = regex.find_iter(&line).find(|m| !m.is_empty())?;
+                let x = Row::new(&line[..m.start()]).last_x();
+                Some((Pos::new(x, y), line))
+            })
+            .collect()
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    // The encoding of the file on disk, unless it failed to decode
+    pub fn file_encoding(&self) -> Option<Encoding> {
+        (!self.decode_error).then_some(self.saved_encoding)
+    }
+
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    pub fn decode_error(&self) -> bool {
+        self.decode_error
+    }
+
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.line_ending = line_ending;
+    }
+
+    // Ends the run of keys that the next one may continue, as any command
+    // the editor handles itself does
+    pub fn forget_last_key(&mut self) {
+        self.last_key = None;
+    }
+
+    pub fn set_kill_ring(&mut self, kill_ring: &Rc<RefCell<KillRing>>) {
+        self.kill_ring = Rc::clone(kill_ring);
+    }
+
+    pub fn file_exists(&self) -> bool {
+        self.file_path
+            .as_deref()
+            .is_some_and(|s| Path::new(s).exists())
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.byte_len()
+    }
+
+    pub fn link(&self) -> Option<&Link> {
+        self.links.get(self.cursor.y).and_then(|link| link.as_ref())
+    }
+
+    // Moves the cursor to the next row with a link, without wrapping around
+    pub fn next_link(&mut self, backward: bool) -> Option<Link> {
+        let has_link = |y: &usize| self.links.get(*y).is_some_and(|link| link.is_some());
+        let y = if backward {
+            (0..self.cursor.y).rev().find(has_link)?
+        } else {
+            ((self.cursor.y + 1)..self.links.len()).find(has_link)?
+        };
+        self.goto(Pos::new(0, y));
+        self.links[y].clone()
+    }
+
     pub fn mark_whole(&mut self) {
         if let Some(anchor) = self.anchor {
             self.unhighlight_region(anchor);
@@ -934,30 +1751,162 @@ impl Buffer {
 
     pub fn save(&mut self) -> io::Result<()> {
         if let Some(file_path) = self.file_path.as_deref() {
-            let file = File::create(file_path)?;
-            let mut writer = BufWriter::new(file);
-            let len = self.rows.len();
+            let text = self.rows.text(self.line_ending.as_str());
+            let bytes = self.encoding.encode(&text)?;
 
-            for (i, row) in self.rows.iter_mut().enumerate() {
-                writer.write(row.string.as_bytes())?;
-                if i < len - 1 {
-                    writer.write(b"\n")?;
-                }
-                row.context = None;
-            }
+            write_atomic(Path::new(file_path), |writer| writer.write_all(&bytes))?;
+            // losing the undo history is not worth failing the save
+            UndoFile::save(file_path, &bytes, &self.undo_tree).unwrap_or(());
+            auto_save::remove(file_path).unwrap_or(());
+            self.disk_stamp = Self::disk_stamp_of(file_path);
+
+            self.rows.clear_contexts();
 
             self.syntax = <dyn Syntax>::detect(Some(file_path));
             self.anchor = None;
             self.last_key = None;
-            self.syntax_update(0);
+            self.highlighted = 0;
+            self.draw_range.full_expand();
 
-            self.saved_time = self.undo_list.last().map(|e| e.time);
+            self.saved_time = self.undo_tree.time();
+            self.auto_saved_time = self.saved_time;
+            self.recoverable = false;
+            self.saved_encoding = self.encoding;
+            self.saved_line_ending = self.line_ending;
         }
         Ok(())
     }
 
     pub fn save_as(&mut self, file_path: &str) -> io::Result<()> {
-        self.file_path = Some(String::from(file_path));
-        self.save()
+        let name = mem::replace(&mut self.name, Self::name_of(Some(file_path)));
+        let prev_file_path = self.file_path.replace(String::from(file_path));
+
+        let result = self.save();
+        match result {
+            Ok(()) => {
+                if let Some(prev_file_path) = prev_file_path.as_deref() {
+                    auto_save::remove(prev_file_path).unwrap_or(());
+                }
+            }
+            Err(_) => {
+                self.name = name;
+                self.file_path = prev_file_path;
+            }
+        }
+        result
+    }
+
+    // Whether another program has written the file since it was read or saved
+    pub fn changed_on_disk(&self) -> bool {
+        match self.file_path.as_deref().and_then(Self::disk_stamp_of) {
+            Some(stamp) => self.disk_stamp != Some(stamp),
+            None => false,
+        }
+    }
+
+    fn disk_stamp_of(file_path: &str) -> Option<(SystemTime, u64)> {
+        let metadata = fs::metadata(file_path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    }
+
+    // Whether the file has been backed up in this session
+    pub fn backed_up(&self) -> bool {
+        self.backed_up
+    }
+
+    pub fn set_backed_up(&mut self) {
+        self.backed_up = true;
+    }
+
+    // Writes the unsaved changes to the auto-save file, if not done yet
+    pub fn auto_save(&mut self) -> io::Result<()> {
+        let file_path = match self.file_path.as_deref() {
+            Some(file_path) => file_path,
+            None => return Ok(()),
+        };
+        let time = self.undo_tree.time();
+        if time == self.auto_saved_time || !self.modified() {
+            return Ok(());
+        }
+
+        let text = self.rows.read_str(Pos::new(0, 0), self.rows.last_pos());
+        auto_save::write(file_path, &text)?;
+        self.auto_saved_time = time;
+        Ok(())
+    }
+
+    // Whether a newer auto-save file was found when the file was opened
+    pub fn recoverable(&self) -> bool {
+        self.recoverable
+    }
+
+    pub fn skip_recovery(&mut self) {
+        self.recoverable = false;
+    }
+
+    // Replaces the text with the auto-saved one as a single undo step
+    pub fn recover(&mut self) -> io::Result<()> {
+        self.recoverable = false;
+        let text = match self.file_path.as_deref() {
+            Some(file_path) => auto_save::read(file_path)?,
+            None => return Ok(()),
+        };
+        if let Some(anchor) = self.anchor {
+            self.unhighlight_region(anchor);
+            self.anchor = None;
+        }
+
+        let time = self.time();
+        let edit = Edit::remove(time, Pos::new(0, 0), self.rows.last_pos(), false);
+        let edit = self.process_edit(edit);
+        self.push_edit(edit);
+        let edit = Edit::insert(time, Pos::new(0, 0), text, false);
+        let edit = self.process_edit(edit);
+        self.push_edit(edit);
+
+        self.auto_saved_time = self.undo_tree.time();
+        self.last_key = None;
+        self.draw_range.full_expand();
+        self.scroll();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer() -> Buffer {
+        let mut buffer = Buffer::blank(None);
+        buffer.resize(Pos::new(0, 0), Size::new(80, 20));
+        buffer
+    }
+
+    // Undo as a command does not go through `process_key`
+    #[test]
+    fn type_after_undo_command() {
+        let mut buffer = buffer();
+        buffer.process_key(Key::Char('a'));
+        buffer.undo();
+        buffer.process_key(Key::Char('b'));
+        assert_eq!(buffer.rows.text("\n"), "b");
+
+        buffer.process_key(Key::Char('c'));
+        buffer.undo();
+        buffer.process_key(Key::Char('d'));
+        assert_eq!(buffer.rows.text("\n"), "d");
+        buffer.undo();
+        assert_eq!(buffer.rows.text("\n"), "");
+    }
+
+    #[test]
+    fn newline_after_undo_command() {
+        let mut buffer = buffer();
+        buffer.process_key(Key::Ctrl(b'M'));
+        buffer.undo();
+        buffer.process_key(Key::Ctrl(b'M'));
+        assert_eq!(buffer.rows.text("\n"), "\n");
+        buffer.undo();
+        assert_eq!(buffer.rows.text("\n"), "");
     }
 }
@@ -12,9 +12,9 @@ pub enum Term {
 
 impl Term {
     fn detect() -> Self {
-        if env::var("COLORTERM").map_or(false, |v| v == "truecolor") {
+        if env::var("COLORTERM").is_ok_and(|v| v == "truecolor") {
             Self::TrueColor
-        } else if env::var("TERM").map_or(false, |v| v.contains("256color")) {
+        } else if env::var("TERM").is_ok_and(|v| v.contains("256color")) {
             Self::Color256
         } else {
             Self::Color16
@@ -0,0 +1,172 @@
+use ::clipboard::{ClipboardContext, ClipboardProvider};
+use std::env;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+pub trait Clipboard {
+    // None means the contents cannot be read through this backend
+    fn get(&mut self) -> Option<String>;
+    fn set(&mut self, string: &str);
+}
+
+impl dyn Clipboard {
+    // ELE_CLIPBOARD forces one of: system, wl-copy, xclip, pbcopy, osc52, internal
+    pub fn detect() -> Box<Self> {
+        let name = env::var("ELE_CLIPBOARD").unwrap_or_default();
+        if !name.is_empty() {
+            if let Some(clipboard) = Self::by_name(&name) {
+                return clipboard;
+            }
+        }
+
+        if env::var_os("WAYLAND_DISPLAY").is_some() && in_path("wl-copy") {
+            return Self::by_name("wl-copy").unwrap();
+        }
+        if cfg!(any(target_os = "macos", target_os = "windows")) || env::var_os("DISPLAY").is_some()
+        {
+            if let Some(clipboard) = Self::by_name("system") {
+                return clipboard;
+            }
+            if in_path("xclip") {
+                return Self::by_name("xclip").unwrap();
+            }
+        }
+        if cfg!(target_os = "macos") && in_path("pbcopy") {
+            return Self::by_name("pbcopy").unwrap();
+        }
+        if env::var_os("SSH_TTY").is_some() {
+            return Box::new(Osc52);
+        }
+        Box::new(Internal)
+    }
+
+    fn by_name(name: &str) -> Option<Box<Self>> {
+        match name {
+            "system" => match ClipboardContext::new() {
+                Ok(context) => Some(Box::new(System(context))),
+                Err(_) => None,
+            },
+            "wl-copy" => Some(Box::new(External {
+                copy: &["wl-copy"],
+                paste: &["wl-paste", "--no-newline"],
+            })),
+            "xclip" => Some(Box::new(External {
+                copy: &["xclip", "-selection", "clipboard"],
+                paste: &["xclip", "-selection", "clipboard", "-o"],
+            })),
+            "pbcopy" => Some(Box::new(External {
+                copy: &["pbcopy"],
+                paste: &["pbpaste"],
+            })),
+            "osc52" => Some(Box::new(Osc52)),
+            "internal" => Some(Box::new(Internal)),
+            _ => None,
+        }
+    }
+}
+
+// The clipboard crate (X11, macOS and Windows)
+struct System(ClipboardContext);
+
+impl Clipboard for System {
+    fn get(&mut self) -> Option<String> {
+        self.0.get_contents().ok()
+    }
+
+    fn set(&mut self, string: &str) {
+        self.0.set_contents(string.into()).unwrap_or(());
+    }
+}
+
+struct External {
+    copy: &'static [&'static str],
+    paste: &'static [&'static str],
+}
+
+impl Clipboard for External {
+    fn get(&mut self) -> Option<String> {
+        let output = Command::new(self.paste[0])
+            .args(&self.paste[1..])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        if output.status.success() {
+            String::from_utf8(output.stdout).ok()
+        } else {
+            None
+        }
+    }
+
+    fn set(&mut self, string: &str) {
+        let child = Command::new(self.copy[0])
+            .args(&self.copy[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        if let Ok(mut child) = child {
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(string.as_bytes()).unwrap_or(());
+            }
+            child.wait().unwrap_or_default();
+        }
+    }
+}
+
+// Copies through the terminal, which works over SSH; pasting is not supported
+struct Osc52;
+
+impl Clipboard for Osc52 {
+    fn get(&mut self) -> Option<String> {
+        None
+    }
+
+    fn set(&mut self, string: &str) {
+        let sequence = format!("\x1b]52;c;{}\x07", base64(string.as_bytes()));
+        let sequence = if env::var_os("TMUX").is_some() {
+            format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"))
+        } else {
+            sequence
+        };
+        let mut stdout = io::stdout();
+        stdout.write_all(sequence.as_bytes()).unwrap_or(());
+        stdout.flush().unwrap_or(());
+    }
+}
+
+// Kills stay inside the editor
+pub struct Internal;
+
+impl Clipboard for Internal {
+    fn get(&mut self) -> Option<String> {
+        None
+    }
+
+    fn set(&mut self, _string: &str) {}
+}
+
+fn in_path(command: &str) -> bool {
+    env::var_os("PATH")
+        .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(command).is_file()))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const TABLE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut string = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0, |n, (i, &b)| n | (b as u32) << (16 - i * 8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                string.push(TABLE[(n >> (18 - i * 6) & 0x3f) as usize] as char);
+            } else {
+                string.push('=');
+            }
+        }
+    }
+    string
+}
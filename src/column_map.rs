@@ -0,0 +1,358 @@
+use crate::row::{char_width, TAB_WIDTH};
+
+// Rows are split into chunks of about this many bytes, and conversions
+// scan a chunk from its start
+const CHUNK_LEN: usize = 256;
+
+// The length and the display width of some text. Tab stops make the width
+// depend on the column it starts at, so one is kept for each remainder.
+#[derive(Clone, Copy, Default)]
+struct Span {
+    len: usize,
+    widths: [usize; TAB_WIDTH],
+}
+
+impl Span {
+    fn new(string: &str) -> Self {
+        let mut widths = [0; TAB_WIDTH];
+        for (r, width) in widths.iter_mut().enumerate() {
+            *width = string.chars().fold(0, |w, ch| w + char_width(r + w, ch));
+        }
+        Self {
+            len: string.len(),
+            widths,
+        }
+    }
+
+    fn width(&self, x: usize) -> usize {
+        self.widths[x % TAB_WIDTH]
+    }
+
+    fn concat(&self, other: &Self) -> Self {
+        let mut widths = [0; TAB_WIDTH];
+        for (r, width) in widths.iter_mut().enumerate() {
+            let w = self.widths[r];
+            *width = w + other.widths[(r + w) % TAB_WIDTH];
+        }
+        Self {
+            len: self.len + other.len,
+            widths,
+        }
+    }
+}
+
+type Tree = Option<Box<Node>>;
+
+// A treap of chunks in the order they appear in the row
+struct Node {
+    span: Span,
+    // the span and the number of the chunks in this subtree
+    sum: Span,
+    count: usize,
+    priority: u32,
+    left: Tree,
+    right: Tree,
+}
+
+impl Node {
+    fn new(span: Span, priority: u32) -> Self {
+        Self {
+            span,
+            sum: span,
+            count: 1,
+            priority,
+            left: None,
+            right: None,
+        }
+    }
+
+    fn update(&mut self) {
+        self.sum = self.span;
+        self.count = 1;
+        if let Some(left) = self.left.as_ref() {
+            self.sum = left.sum.concat(&self.sum);
+            self.count += left.count;
+        }
+        if let Some(right) = self.right.as_ref() {
+            self.sum = self.sum.concat(&right.sum);
+            self.count += right.count;
+        }
+    }
+}
+
+fn merge(tree1: Tree, tree2: Tree) -> Tree {
+    match (tree1, tree2) {
+        (None, tree) | (tree, None) => tree,
+        (Some(mut node1), Some(mut node2)) => {
+            if node1.priority > node2.priority {
+                node1.right = merge(node1.right.take(), Some(node2));
+                node1.update();
+                Some(node1)
+            } else {
+                node2.left = merge(Some(node1), node2.left.take());
+                node2.update();
+                Some(node2)
+            }
+        }
+    }
+}
+
+// Splits a tree into its first n chunks and the rest
+fn split(tree: Tree, n: usize) -> (Tree, Tree) {
+    let mut node = match tree {
+        Some(node) => node,
+        None => return (None, None),
+    };
+    let left_count = node.left.as_ref().map_or(0, |left| left.count);
+
+    if n <= left_count {
+        let (tree1, tree2) = split(node.left.take(), n);
+        node.left = tree2;
+        node.update();
+        (tree1, Some(node))
+    } else {
+        let (tree1, tree2) = split(node.right.take(), n - left_count - 1);
+        node.right = tree1;
+        node.update();
+        (Some(node), tree2)
+    }
+}
+
+// Maps display columns to byte indices of a row and vice versa.
+// Seeking the chunk around a position takes O(log n) time, and so does
+// updating the map for an edit, plus the length of the edit.
+pub struct ColumnMap {
+    root: Tree,
+    seed: u32,
+}
+
+impl ColumnMap {
+    pub fn new(string: &str) -> Self {
+        let mut map = Self {
+            root: None,
+            seed: 0x9e37_79b9,
+        };
+        map.root = map.build(string);
+        map
+    }
+
+    pub fn width(&self) -> usize {
+        self.root.as_ref().map_or(0, |root| root.sum.widths[0])
+    }
+
+    // Returns the byte index and the column of the start of the chunk
+    // containing the byte index
+    pub fn seek_idx(&self, idx: usize) -> (usize, usize) {
+        let (_, start) = self.seek(|end_idx, _| idx < end_idx);
+        start
+    }
+
+    // Returns the byte index and the column of the start of the chunk
+    // containing the column
+    pub fn seek_x(&self, x: usize) -> (usize, usize) {
+        let (_, start) = self.seek(|_, end_x| x < end_x);
+        start
+    }
+
+    // Updates the map after `removed` bytes at `idx` were replaced with
+    // `inserted` bytes, where `string` is the row after the edit
+    pub fn splice(&mut self, string: &str, idx: usize, removed: usize, inserted: usize) {
+        let (first, (start, _)) = self.seek(|end_idx, _| idx < end_idx);
+        let (last, _) = if removed > 0 {
+            self.seek(|end_idx, _| idx + removed - 1 < end_idx)
+        } else {
+            (first, (start, 0))
+        };
+
+        let (tree1, tree2) = split(self.root.take(), first);
+        let (tree2, mut tree3) = split(tree2, last + 1 - first);
+        let mut end = start + tree2.map_or(0, |node| node.sum.len) + inserted - removed;
+
+        // Take in the next chunk rather than leave a small one
+        if end - start < CHUNK_LEN / 2 {
+            let (next, rest) = split(tree3, 1);
+            end += next.map_or(0, |node| node.sum.len);
+            tree3 = rest;
+        }
+
+        let tree2 = self.build(&string[start..end]);
+        self.root = merge(merge(tree1, tree2), tree3);
+    }
+
+    // Finds the first chunk for which the predicate on its end holds, or
+    // the last chunk, returning its number and its start
+    fn seek<F>(&self, pred: F) -> (usize, (usize, usize))
+    where
+        F: Fn(usize, usize) -> bool,
+    {
+        let mut tree = self.root.as_ref();
+        let (mut n, mut idx, mut x) = (0, 0, 0);
+        let mut found = (0, (0, 0));
+
+        while let Some(node) = tree {
+            if let Some(left) = node.left.as_ref() {
+                let end_idx = idx + left.sum.len;
+                let end_x = x + left.sum.width(x);
+                if pred(end_idx, end_x) {
+                    tree = Some(left);
+                    continue;
+                }
+                n += left.count;
+                idx = end_idx;
+                x = end_x;
+            }
+            found = (n, (idx, x));
+
+            let end_idx = idx + node.span.len;
+            let end_x = x + node.span.width(x);
+            if pred(end_idx, end_x) {
+                break;
+            }
+            n += 1;
+            idx = end_idx;
+            x = end_x;
+            tree = node.right.as_ref();
+        }
+        found
+    }
+
+    // Splits a string into chunks of similar lengths
+    fn build(&mut self, string: &str) -> Tree {
+        let count = string.len().div_ceil(CHUNK_LEN);
+        let mut tree = None;
+        let mut start = 0;
+
+        for i in 1..=count {
+            let mut end = string.len() * i / count;
+            while !string.is_char_boundary(end) {
+                end += 1;
+            }
+            if start < end {
+                let node = Node::new(Span::new(&string[start..end]), self.next_priority());
+                tree = merge(tree, Some(Box::new(node)));
+                start = end;
+            }
+        }
+        tree
+    }
+
+    // xorshift32
+    fn next_priority(&mut self) -> u32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::row::{char_width, Row};
+
+    // Pieces to build rows from, some of them long enough to span chunks
+    const PIECES: [&str; 9] = [
+        "a",
+        "\t",
+        "漢",
+        "\u{200d}",
+        "e\u{301}",
+        " \t字",
+        "🐈\u{200d}⬛",
+        "abc\tdef ",
+        "long ascii text without any tab, which makes chunks of many bytes.. ",
+    ];
+
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 as usize % n
+        }
+    }
+
+    // Returns the byte index and the column of each char boundary, scanning
+    // the row from its start
+    fn scan(string: &str) -> Vec<(usize, usize)> {
+        let mut x = 0;
+        let mut boundaries = Vec::new();
+        for (idx, ch) in string.char_indices() {
+            boundaries.push((idx, x));
+            x += char_width(x, ch);
+        }
+        boundaries.push((string.len(), x));
+        boundaries
+    }
+
+    fn check(row: &Row) {
+        let boundaries = scan(&row.string);
+        let width = boundaries.last().unwrap().1;
+        assert_eq!(row.last_x(), width, "{:?}", row.string);
+
+        // a column maps to the char with width starting at it, or to 0
+        let mut idxs = vec![0; width + 2];
+        for (i, &(idx, x)) in boundaries.iter().enumerate() {
+            let next_x = boundaries.get(i + 1).map_or(x + 1, |&(_, x)| x);
+            if next_x > x {
+                idxs[x] = idx;
+            }
+        }
+        for (x, &idx) in idxs.iter().enumerate() {
+            assert_eq!(row.x_to_idx(x), idx, "x {} of {:?}", x, row.string);
+        }
+        for &(idx, x) in &boundaries {
+            assert_eq!(row.idx_to_x(idx), x, "idx {} of {:?}", idx, row.string);
+        }
+    }
+
+    fn random_x(rng: &mut Rng, row: &Row) -> usize {
+        let boundaries = scan(&row.string);
+        boundaries[rng.next(boundaries.len())].1
+    }
+
+    #[test]
+    fn random_edits() {
+        let mut rng = Rng(0x2545_f491);
+        let mut row = Row::new("\t");
+
+        for i in 1..=3000 {
+            if i % 1000 == 0 {
+                row.remove_str(0, row.last_x());
+                check(&row);
+            }
+            let piece = PIECES[rng.next(PIECES.len())].repeat(1 + rng.next(4));
+            match rng.next(8) {
+                0..=1 => row.push_str(&piece),
+                2..=5 => {
+                    let x = random_x(&mut rng, &row);
+                    row.insert_str(x, &piece);
+                }
+                _ => {
+                    let x1 = random_x(&mut rng, &row);
+                    let x2 = random_x(&mut rng, &row);
+                    row.remove_str(x1.min(x2), x1.max(x2));
+                }
+            }
+            check(&row);
+        }
+    }
+
+    #[test]
+    fn empty_and_append() {
+        let mut row = Row::new(&"漢\t".repeat(300));
+        check(&row);
+
+        row.remove_str(0, row.last_x());
+        assert_eq!(row.string, "");
+        check(&row);
+
+        for piece in PIECES.iter().cycle().take(100) {
+            row.insert_str(row.last_x(), piece);
+            check(&row);
+        }
+        row.truncate(row.last_x() / 2);
+        check(&row);
+    }
+}
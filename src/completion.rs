@@ -0,0 +1,92 @@
+use std::fs;
+use std::path::Path;
+
+use crate::util::{abbreviate_path, expand_path};
+
+pub enum Completion {
+    Path,
+    Names(Vec<String>),
+}
+
+impl Completion {
+    pub fn candidates(&self, input: &str) -> Vec<String> {
+        let mut candidates = match self {
+            Self::Path => Self::path_candidates(input),
+            Self::Names(names) => names
+                .iter()
+                .filter(|name| name.starts_with(input))
+                .cloned()
+                .collect(),
+        };
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
+    fn path_candidates(input: &str) -> Vec<String> {
+        let (dir, prefix) = match input.rfind('/') {
+            Some(i) => input.split_at(i + 1),
+            None => ("", input),
+        };
+        let entries = match fs::read_dir(if dir.is_empty() {
+            ".".into()
+        } else {
+            expand_path(dir)
+        }) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+
+        entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.'))
+                {
+                    return None;
+                }
+                let is_dir = entry.path().is_dir();
+                Some(format!("{}{}{}", dir, name, if is_dir { "/" } else { "" }))
+            })
+            .collect()
+    }
+}
+
+pub fn common_prefix(candidates: &[String]) -> &str {
+    let first = match candidates.first() {
+        Some(first) => first.as_str(),
+        None => return "",
+    };
+    let mut len = first.len();
+
+    for candidate in candidates[1..].iter() {
+        len = first
+            .char_indices()
+            .zip(candidate.chars())
+            .take_while(|((_, ch1), ch2)| ch1 == ch2)
+            .map(|((i, ch), _)| i + ch.len_utf8())
+            .last()
+            .unwrap_or(0)
+            .min(len);
+    }
+    &first[..len]
+}
+
+pub fn initial_dir(file_path: Option<&str>) -> String {
+    let dir = file_path
+        .and_then(|s| Path::new(s).parent())
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+
+    match fs::canonicalize(dir) {
+        Ok(dir) => {
+            let dir = abbreviate_path(&dir.to_string_lossy());
+            if dir.ends_with('/') {
+                dir
+            } else {
+                dir + "/"
+            }
+        }
+        Err(_) => String::new(),
+    }
+}
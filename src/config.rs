@@ -0,0 +1,89 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use crate::backup::BackupMode;
+use crate::util::{config_dir, expand_path};
+
+// Settings read from lines such as "backup = numbered" in the config file
+pub struct Config {
+    pub auto_revert: bool,
+    pub backup: BackupMode,
+    pub backup_directory: Option<PathBuf>,
+    pub backup_versions: usize,
+    pub no_backup_directories: Vec<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            auto_revert: false,
+            backup: BackupMode::Off,
+            backup_directory: None,
+            backup_versions: 5,
+            no_backup_directories: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    // Returns the settings along with the first error, skipping invalid lines
+    pub fn load() -> (Self, Option<String>) {
+        let mut config = Self::default();
+        let path = match config_dir() {
+            Some(dir) => dir.join("config"),
+            None => return (config, None),
+        };
+        let string = match fs::read_to_string(&path) {
+            Ok(string) => string,
+            Err(e) if e.kind() == ErrorKind::NotFound => return (config, None),
+            Err(e) => return (config, Some(format!("{}: {}", path.display(), e))),
+        };
+
+        let mut error = None;
+        for (i, line) in string.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Err(message) = config.set(line) {
+                error.get_or_insert(format!("{}:{}: {}", path.display(), i + 1, message));
+            }
+        }
+        (config, error)
+    }
+
+    fn set(&mut self, line: &str) -> Result<(), String> {
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => return Err("Expected NAME = VALUE".into()),
+        };
+        let invalid = || format!("Invalid value for {}: {}", name, value);
+
+        match name {
+            "auto-revert" => {
+                self.auto_revert = match value {
+                    "on" | "true" | "yes" => true,
+                    "off" | "false" | "no" => false,
+                    _ => return Err(invalid()),
+                };
+            }
+            "backup" => {
+                self.backup = BackupMode::from_name(value).ok_or_else(invalid)?;
+            }
+            "backup-directory" => {
+                self.backup_directory = Some(PathBuf::from(expand_path(value)));
+            }
+            "backup-versions" => {
+                self.backup_versions = value.parse().map_err(|_| invalid())?;
+            }
+            "no-backup-directory" => {
+                let dir = expand_path(value);
+                let dir = fs::canonicalize(&dir).unwrap_or_else(|_| PathBuf::from(dir));
+                self.no_backup_directories.push(dir);
+            }
+            _ => return Err(format!("Unknown setting: {}", name)),
+        }
+        Ok(())
+    }
+}
@@ -28,6 +28,7 @@ impl PartialOrd for Pos {
     }
 }
 
+#[derive(Clone, Copy, Default)]
 pub struct Size {
     pub w: usize,
     pub h: usize,
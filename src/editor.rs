@@ -1,48 +1,242 @@
-use signal_hook::{self, consts::signal::SIGWINCH};
+use regex::Regex;
+use signal_hook::{
+    self,
+    consts::signal::{SIGHUP, SIGTERM, SIGWINCH},
+};
+use std::cell::RefCell;
+use std::fs;
 use std::io::{self, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
 use std::str;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::auto_save;
+use crate::backup;
 use crate::buffer::Buffer;
 use crate::canvas::Canvas;
+use crate::clipboard::Clipboard;
+use crate::completion::{self, Completion};
+use crate::config::Config;
 use crate::coord::{Pos, Size};
+use crate::encoding::Encoding;
+use crate::face::{Bg, Fg};
+use crate::grep;
+use crate::history::History;
 use crate::key::{Key, KeyError};
+use crate::kill_ring::KillRing;
+use crate::line_ending::LineEnding;
+use crate::link::Link;
 use crate::minibuffer::Minibuffer;
+use crate::util::{abbreviate_path, expand_path};
+use crate::window::{Layout, Split, Window};
+
+const BUFFER_LIST: &str = "*Buffer List*";
+const OCCUR: &str = "*Occur*";
+const GREP: &str = "*Grep*";
+const UNDO_TREE: &str = "*Undo Tree*";
+
+// auto-save after this many keystrokes or this much time
+const AUTO_SAVE_INTERVAL: usize = 300;
+const AUTO_SAVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// how often auto-revert mode checks the files on disk
+const AUTO_REVERT_INTERVAL: Duration = Duration::from_secs(1);
+
+const COMMANDS: &[&str] = &[
+    "auto-revert-mode",
+    "grep-regexp",
+    "grep-string",
+    "next-error",
+    "occur",
+    "previous-error",
+    "query-replace",
+    "query-replace-regexp",
+    "redo",
+    "replace-regexp",
+    "replace-string",
+    "revert-buffer",
+    "revert-with-encoding",
+    "set-encoding",
+    "set-line-ending",
+    "undo",
+    "undo-switch-branch",
+    "undo-tree-visualize",
+    "visual-line-mode",
+];
 
 #[derive(PartialEq)]
 enum State {
     Default,
-    Search { backward: bool },
+    Search {
+        backward: bool,
+        regex: bool,
+    },
     GotoLine,
+    Command,
+    Occur,
+    GrepPattern {
+        regex: bool,
+    },
+    GrepDir {
+        regex: bool,
+        pattern: String,
+    },
+    ReplaceFrom {
+        regex: bool,
+        query: bool,
+    },
+    ReplaceTo {
+        regex: bool,
+        query: bool,
+        from: String,
+    },
+    QueryReplace,
+    SetEncoding {
+        revert: bool,
+    },
+    SetLineEnding,
     CtrlX,
     Save,
-    Quit,
+    MakeDir {
+        file_path: Option<String>,
+    },
+    FindFile,
+    SwitchBuffer,
+    KillBuffer,
+    KillModified {
+        index: usize,
+    },
+    Quit {
+        index: usize,
+    },
+    Recover {
+        index: usize,
+    },
+    SaveChanged,
+    Revert {
+        encoding: Option<Encoding>,
+    },
     Quitted,
 }
 
+#[derive(Clone, Copy, PartialEq)]
+enum CaseMode {
+    Smart,
+    Sensitive,
+    Insensitive,
+}
+
+impl CaseMode {
+    fn next(self) -> Self {
+        match self {
+            Self::Smart => Self::Sensitive,
+            Self::Sensitive => Self::Insensitive,
+            Self::Insensitive => Self::Smart,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Smart => "smart case",
+            Self::Sensitive => "case sensitive",
+            Self::Insensitive => "case insensitive",
+        }
+    }
+
+    // Smart case ignores case unless the query has an uppercase letter,
+    // not counting the escape sequences of a regexp such as \W
+    fn ignores_case(self, input: &str, regex: bool) -> bool {
+        match self {
+            Self::Smart => {
+                let mut escaped = false;
+                !input.chars().any(|ch| {
+                    let upper = ch.is_uppercase() && !escaped;
+                    escaped = regex && ch == '\\' && !escaped;
+                    upper
+                })
+            }
+            Self::Sensitive => false,
+            Self::Insensitive => true,
+        }
+    }
+}
+
 pub struct Editor {
     stdin: io::Stdin,
     stdout: io::Stdout,
     canvas: Canvas,
     state: State,
-    buffer: Buffer,
+    buffers: Vec<Buffer>,
+    history: Vec<usize>,
+    windows: Vec<Window>,
+    layout: Layout,
+    focus: usize,
+    borders: Vec<(Pos, usize)>,
+    results: Option<String>,
+    kill_ring: Rc<RefCell<KillRing>>,
+    case_mode: CaseMode,
+    input_history: History,
+    config: Config,
     minibuffer: Minibuffer,
+    screen_size: Size,
     screen_resized: Arc<AtomicBool>,
+    // the signal that terminated the editor, or 0
+    terminated: Arc<AtomicUsize>,
+    keystrokes: usize,
+    auto_saved_at: Instant,
+    auto_revert: bool,
+    auto_reverted_at: Instant,
 }
 
 impl Editor {
-    pub fn new(file_path: Option<&str>) -> io::Result<Self> {
+    pub fn new(file_paths: &[String], encoding: Option<Encoding>) -> io::Result<Self> {
+        let (config, config_error) = Config::load();
+        let auto_revert = config.auto_revert;
         let mut editor = Self {
             stdin: io::stdin(),
             stdout: io::stdout(),
             canvas: Canvas::new(),
             state: State::Default,
-            buffer: Buffer::new(file_path)?,
+            buffers: Vec::new(),
+            history: Vec::new(),
+            windows: vec![Window::new(0)],
+            layout: Layout::Leaf(0),
+            focus: 0,
+            borders: Vec::new(),
+            results: None,
+            kill_ring: Rc::new(RefCell::new(KillRing::new(<dyn Clipboard>::detect()))),
+            case_mode: CaseMode::Smart,
+            input_history: History::load(),
+            config,
             minibuffer: Minibuffer::new(),
+            screen_size: Size::new(0, 0),
             screen_resized: Arc::new(AtomicBool::new(true)),
+            terminated: Arc::new(AtomicUsize::new(0)),
+            keystrokes: 0,
+            auto_saved_at: Instant::now(),
+            auto_revert,
+            auto_reverted_at: Instant::now(),
         };
 
+        for file_path in file_paths {
+            let buffer = Buffer::new(Some(file_path), encoding)?;
+            editor.add_buffer(buffer);
+        }
+        if editor.buffers.is_empty() {
+            let buffer = Buffer::new(None, None)?;
+            editor.add_buffer(buffer);
+        }
+        editor.switch_buffer(0);
+        let message = Self::open_message(&editor.buffers[0]);
+        editor.minibuffer.set_message(&message);
+        if let Some(error) = config_error {
+            editor.minibuffer.set_message(&error);
+        }
+        editor.offer_recovery(0);
+
         // switch to alternate screen buffer
         editor.stdout.write(b"\x1b[?1049h")?;
         editor.stdout.flush()?;
@@ -50,20 +244,46 @@ impl Editor {
         // detect screen resizing
         signal_hook::flag::register(SIGWINCH, Arc::clone(&editor.screen_resized))?;
 
+        // auto-save before quitting on hangup or kill
+        for signal in [SIGHUP, SIGTERM] {
+            let terminated = Arc::clone(&editor.terminated);
+            signal_hook::flag::register_usize(signal, terminated, signal as usize)?;
+        }
+
         Ok(editor)
     }
 
     pub fn run(&mut self) -> io::Result<()> {
         while self.state != State::Quitted {
+            if self.terminated_by().is_some() {
+                self.auto_save();
+                break;
+            }
             if self.screen_resized.swap(false, Ordering::Relaxed) {
                 self.resize()?;
             }
+            if self.auto_save_due() {
+                self.auto_save();
+            }
+            if self.auto_revert_due() {
+                self.auto_revert();
+            }
 
             self.draw()?;
 
             match self.read_key() {
-                Ok(key) => self.process_key(key)?,
-                Err(KeyError::IoError(e)) => return Err(e),
+                Ok(key) => {
+                    if let Err(e) = self.process_key(key) {
+                        self.minibuffer.set_message(&e.to_string());
+                        self.state = State::Default;
+                    }
+                    self.sync_windows();
+                    self.keystrokes += 1;
+                }
+                Err(KeyError::IoError(e)) => {
+                    self.auto_save();
+                    return Err(e);
+                }
                 _ => (),
             }
         }
@@ -96,7 +316,8 @@ impl Editor {
             }
         }
 
-        self.buffer.resize(Pos::new(0, 0), Size::new(w, h - 2));
+        self.screen_size = Size::new(w, h);
+        self.arrange();
         self.minibuffer.resize(Pos::new(0, h - 1), Size::new(w, 1));
         Ok(())
     }
@@ -104,14 +325,53 @@ impl Editor {
     fn draw(&mut self) -> io::Result<()> {
         self.canvas.write(b"\x1b[?25l")?;
 
-        self.buffer.draw(&mut self.canvas)?;
+        for (i, window) in self.windows.iter_mut().enumerate() {
+            let buffer = &mut self.buffers[window.buffer];
+            if i == self.focus {
+                buffer.draw(&mut self.canvas, true)?;
+            } else {
+                buffer.swap_view(&mut window.view);
+                let result = buffer.draw(&mut self.canvas, false);
+                buffer.swap_view(&mut window.view);
+                result?;
+            }
+        }
+        for &(pos, h) in self.borders.iter() {
+            self.canvas.set_fg_color(Fg::Default)?;
+            self.canvas.set_bg_color(Bg::StatusBar)?;
+            for y in pos.y..(pos.y + h) {
+                self.canvas.set_cursor(pos.x, y)?;
+                self.canvas.write("│".as_bytes())?;
+            }
+        }
         self.minibuffer.draw(&mut self.canvas)?;
 
         match self.state {
             State::Default | State::CtrlX => {
-                self.buffer.draw_cursor(&mut self.canvas)?;
+                let current = self.current();
+                self.buffers[current].draw_cursor(&mut self.canvas)?;
             }
-            State::Search { .. } | State::GotoLine | State::Save | State::Quit => {
+            State::Search { .. }
+            | State::GotoLine
+            | State::Command
+            | State::Occur
+            | State::GrepPattern { .. }
+            | State::GrepDir { .. }
+            | State::ReplaceFrom { .. }
+            | State::ReplaceTo { .. }
+            | State::QueryReplace
+            | State::SetEncoding { .. }
+            | State::SetLineEnding
+            | State::Save
+            | State::MakeDir { .. }
+            | State::FindFile
+            | State::SwitchBuffer
+            | State::KillBuffer
+            | State::KillModified { .. }
+            | State::Quit { .. }
+            | State::Recover { .. }
+            | State::SaveChanged
+            | State::Revert { .. } => {
                 self.minibuffer.draw_cursor(&mut self.canvas)?;
             }
             State::Quitted => unreachable!(),
@@ -124,13 +384,26 @@ impl Editor {
         self.stdout.flush()
     }
 
+    // The signal that terminated the editor, if any
+    pub fn terminated_by(&self) -> Option<i32> {
+        match self.terminated.load(Ordering::Relaxed) {
+            0 => None,
+            signal => Some(signal as i32),
+        }
+    }
+
     fn read_key(&mut self) -> Result<Key, KeyError> {
         let mut buf = [0];
 
         while self.stdin.read(&mut buf)? == 0 {
-            if self.screen_resized.load(Ordering::Relaxed) {
+            if self.screen_resized.load(Ordering::Relaxed)
+                || self.terminated_by().is_some()
+                || self.auto_save_due()
+                || self.auto_revert_due()
+            {
                 return Err(KeyError::Interrupted);
             }
+            self.highlight_more();
         }
 
         match buf[0] {
@@ -184,53 +457,93 @@ impl Editor {
     }
 
     fn process_key(&mut self, key: Key) -> io::Result<()> {
+        let current = self.current();
+        // keys read in any other state make up a command of the editor,
+        // which the buffer must not take as its previous key, e.g. for M-y
+        if self.state != State::Default {
+            self.buffers[current].forget_last_key();
+        }
+
         match self.state {
             State::Default => match key {
-                Key::Ctrl(b'R') => {
-                    self.minibuffer.set_prompt("Search: ");
-                    self.state = State::Search { backward: true };
-                }
-                Key::Ctrl(b'S') => {
-                    self.minibuffer.set_prompt("Search: ");
-                    self.state = State::Search { backward: false };
-                }
+                Key::Ctrl(b'R') => self.start_search(true, false),
+                Key::Ctrl(b'S') => self.start_search(false, false),
+                // C-M-r
+                Key::Alt(b'\x12') => self.start_search(true, true),
+                // C-M-s
+                Key::Alt(b'\x13') => self.start_search(false, true),
                 Key::Ctrl(b'X') => {
                     self.minibuffer.set_message("C-x");
                     self.state = State::CtrlX;
                 }
                 Key::Alt(b'g') => {
                     self.minibuffer.set_prompt("Goto line: ");
+                    self.minibuffer
+                        .set_history(self.input_history.get("goto-line"));
                     self.state = State::GotoLine;
                 }
+                Key::Alt(b'%') => {
+                    self.run_command("query-replace");
+                }
+                Key::Alt(b'x') => {
+                    let names = COMMANDS.iter().map(|s| s.to_string()).collect();
+                    self.minibuffer.set_prompt("M-x ");
+                    self.minibuffer.set_completion(Completion::Names(names));
+                    self.minibuffer
+                        .set_history(self.input_history.get("command"));
+                    self.state = State::Command;
+                }
+                Key::Ctrl(b'J' | b'M') if self.buffers[current].link().is_some() => {
+                    self.buffers[current].forget_last_key();
+                    let link = self.buffers[current].link().cloned().unwrap();
+                    self.follow_link(link);
+                }
                 _ => {
-                    let message = self.buffer.process_key(key);
+                    let message = self.buffers[current].process_key(key);
                     self.minibuffer.set_message(message);
                 }
             },
-            State::Search { backward } => match key {
+            State::Search { backward, regex } => match key {
                 Key::Ctrl(b'G') => {
-                    self.buffer.clear_matches(true);
+                    self.buffers[current].clear_matches(true);
                     self.minibuffer.set_message("");
                     self.state = State::Default;
                 }
                 Key::Ctrl(b'J' | b'M') => {
-                    self.buffer.clear_matches(false);
+                    let kind = Self::search_kind(regex);
+                    self.input_history.add(kind, self.minibuffer.get_input());
+                    self.buffers[current].clear_matches(false);
                     self.minibuffer.set_message("");
                     self.state = State::Default;
                 }
-                Key::Ctrl(b'N' | b'S') => {
-                    self.buffer.next_match(false);
+                // repeat the previous search when the input is empty
+                Key::Ctrl(b'S' | b'R') | Key::Alt(b'\x13' | b'\x12')
+                    if self.minibuffer.get_input().is_empty() =>
+                {
+                    let kind = Self::search_kind(regex);
+                    if let Some(input) = self.input_history.last(kind) {
+                        self.minibuffer.set_input(input);
+                        self.update_search(backward, regex);
+                    }
+                }
+                Key::Ctrl(b'N' | b'S') | Key::Alt(b'\x13') => {
+                    self.buffers[current].next_match(false);
                 }
-                Key::Ctrl(b'P' | b'R') => {
-                    self.buffer.next_match(true);
+                Key::Ctrl(b'P' | b'R') | Key::Alt(b'\x12') => {
+                    self.buffers[current].next_match(true);
+                }
+                Key::Alt(b'c') => {
+                    self.case_mode = self.case_mode.next();
+                    let input = self.minibuffer.get_input().to_string();
+                    self.start_search(backward, regex);
+                    self.minibuffer.set_input(&input);
+                    self.update_search(backward, regex);
                 }
                 _ => {
                     let prev_input = self.minibuffer.get_input().to_string();
                     self.minibuffer.process_key(key);
-                    let input = self.minibuffer.get_input();
-                    if input != &prev_input {
-                        self.buffer.clear_matches(true);
-                        self.buffer.search(input, backward);
+                    if self.minibuffer.get_input() != prev_input {
+                        self.update_search(backward, regex);
                     }
                 }
             },
@@ -242,41 +555,307 @@ impl Editor {
                 Key::Ctrl(b'J' | b'M') => {
                     let input = self.minibuffer.get_input();
                     if let Ok(num) = input.parse::<usize>() {
-                        self.buffer.goto_line(num);
+                        self.input_history.add("goto-line", input);
+                        self.buffers[current].goto_line(num);
+                        self.minibuffer.set_message("");
+                        self.state = State::Default;
+                    }
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::Command => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let input = self.minibuffer.get_input().to_string();
+                    if COMMANDS.contains(&input.as_str()) {
+                        self.input_history.add("command", &input);
+                        self.run_command(&input);
+                    } else {
+                        self.minibuffer.set_hint(" [No match]");
+                    }
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::Occur => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let input = self.minibuffer.get_input().to_string();
+                    if input.is_empty() {
+                        return Ok(());
+                    }
+                    match Self::compile_query(&input, true, self.case_mode) {
+                        Ok(re) => {
+                            self.input_history.add("regexp-search", &input);
+                            self.state = State::Default;
+                            self.occur(&re, &input);
+                        }
+                        Err(e) => self.minibuffer.set_hint(&format!(" [{}]", e)),
+                    }
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::GrepPattern { regex } => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let pattern = self.minibuffer.get_input().to_string();
+                    if pattern.is_empty() {
+                        return Ok(());
+                    }
+                    if let Err(e) = Self::compile_query(&pattern, regex, self.case_mode) {
+                        self.minibuffer.set_hint(&format!(" [{}]", e));
+                        return Ok(());
+                    }
+                    self.input_history.add(Self::search_kind(regex), &pattern);
+
+                    let file_path = self.buffers[current].file_path.as_deref();
+                    let dir = expand_path(&completion::initial_dir(file_path));
+                    let root = grep::project_root(Path::new(&dir));
+                    let root = abbreviate_path(&root.to_string_lossy());
+                    let root = if root.ends_with('/') {
+                        root
+                    } else {
+                        root + "/"
+                    };
+
+                    self.minibuffer
+                        .set_prompt(&format!("Grep for {} in: ", pattern));
+                    self.minibuffer.set_input(&root);
+                    self.minibuffer.set_completion(Completion::Path);
+                    self.minibuffer.set_history(self.input_history.get("file"));
+                    self.state = State::GrepDir { regex, pattern };
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::GrepDir { regex, ref pattern } => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let dir = expand_path(self.minibuffer.get_input());
+                    if !Path::new(&dir).is_dir() {
+                        self.minibuffer.set_hint(" [Not a directory]");
+                        return Ok(());
+                    }
+                    let pattern = pattern.clone();
+                    let re = Self::compile_query(&pattern, regex, self.case_mode).unwrap();
+                    self.input_history.add("file", self.minibuffer.get_input());
+                    self.state = State::Default;
+                    self.grep(&re, &pattern, &dir);
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::ReplaceFrom { regex, query } => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let from = self.minibuffer.get_input().to_string();
+                    if from.is_empty() {
+                        return Ok(());
+                    }
+                    if let Err(e) = Self::compile_query(&from, regex, self.case_mode) {
+                        self.minibuffer.set_hint(&format!(" [{}]", e));
+                        return Ok(());
+                    }
+                    self.input_history.add("replace", &from);
+                    let prompt = format!("{} {} with: ", Self::replace_title(regex, query), from);
+                    self.minibuffer.set_prompt(&prompt);
+                    self.minibuffer
+                        .set_history(self.input_history.get("replace"));
+                    self.state = State::ReplaceTo { regex, query, from };
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::ReplaceTo {
+                regex,
+                query,
+                ref from,
+            } => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let to = self.minibuffer.get_input().to_string();
+                    self.input_history.add("replace", &to);
+                    let re = Self::compile_query(from, regex, self.case_mode).unwrap();
+                    let prompt = format!("Query replacing {} with {}: (y, n, !, ., q) ", from, to);
+                    let buffer = &mut self.buffers[current];
+
+                    if !buffer.start_replace(re, regex, to, !regex) {
+                        self.minibuffer.set_message("No matches");
+                        self.state = State::Default;
+                    } else if query {
+                        self.minibuffer.set_prompt(&prompt);
+                        self.state = State::QueryReplace;
+                    } else {
+                        buffer.replace_all();
+                        self.finish_replace();
+                    }
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::QueryReplace => match key {
+                Key::Char('y' | ' ') => {
+                    let found = self.buffers[current].replace_match();
+                    if !found {
+                        self.finish_replace();
+                    }
+                }
+                Key::Char('n') | Key::Delete | Key::Backspace => {
+                    let found = self.buffers[current].skip_match();
+                    if !found {
+                        self.finish_replace();
+                    }
+                }
+                Key::Char('!') => {
+                    self.buffers[current].replace_all();
+                    self.finish_replace();
+                }
+                Key::Char('.') => {
+                    self.buffers[current].replace_match();
+                    self.finish_replace();
+                }
+                Key::Char('q') | Key::Ctrl(b'G' | b'J' | b'M') | Key::Escape => {
+                    self.finish_replace();
+                }
+                _ => (),
+            },
+            State::SetEncoding { revert } => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let input = self.minibuffer.get_input();
+                    match Encoding::for_label(input) {
+                        Some(encoding) => {
+                            self.input_history.add("encoding", input);
+                            self.state = State::Default;
+                            if revert {
+                                self.query_revert(Some(encoding));
+                            } else {
+                                self.buffers[current].set_encoding(encoding);
+                                self.minibuffer.set_message("");
+                            }
+                        }
+                        None => self.minibuffer.set_hint(" [Unknown encoding]"),
+                    }
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::SetLineEnding => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let input = self.minibuffer.get_input();
+                    if let Some(line_ending) = LineEnding::from_name(input) {
+                        self.buffers[current].set_line_ending(line_ending);
                         self.minibuffer.set_message("");
                         self.state = State::Default;
+                    } else {
+                        self.minibuffer.set_hint(" [No match]");
                     }
                 }
                 _ => self.minibuffer.process_key(key),
             },
             State::CtrlX => match key {
                 Key::Ctrl(b'S') => {
-                    if self.buffer.file_path.is_none() {
+                    let buffer = &mut self.buffers[current];
+                    if buffer.file_path.is_none() {
                         self.minibuffer.set_prompt("Save as: ");
+                        self.minibuffer.set_completion(Completion::Path);
+                        self.minibuffer.set_history(self.input_history.get("file"));
                         self.state = State::Save;
-                    } else if self.buffer.modified() {
-                        self.buffer.save()?;
-                        self.minibuffer.set_message("Saved");
-                        self.state = State::Default;
+                    } else if buffer.modified() || !buffer.file_exists() {
+                        self.save(None)?;
                     } else {
                         self.minibuffer.set_message("(No changes need to be saved)");
                         self.state = State::Default;
                     }
                 }
                 Key::Ctrl(b'C') => {
-                    if self.buffer.modified() {
-                        self.minibuffer
-                            .set_prompt("Quit without saving changes? (Y/n): ");
-                        self.state = State::Quit;
-                    } else {
-                        self.state = State::Quitted;
-                    }
+                    self.quit(0);
+                }
+                Key::Ctrl(b'F') => {
+                    let file_path = self.buffers[current].file_path.as_deref();
+                    let dir = completion::initial_dir(file_path);
+                    self.minibuffer.set_prompt("Find file: ");
+                    self.minibuffer.set_input(&dir);
+                    self.minibuffer.set_completion(Completion::Path);
+                    self.minibuffer.set_history(self.input_history.get("file"));
+                    self.state = State::FindFile;
+                }
+                Key::Char('`') => {
+                    self.next_error(false);
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'B') => {
+                    self.list_buffers();
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Char('0') => {
+                    self.delete_window();
+                    self.state = State::Default;
+                }
+                Key::Char('1') => {
+                    self.delete_other_windows();
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Char('2') => {
+                    self.split_window(Split::Below);
+                    self.state = State::Default;
+                }
+                Key::Char('3') => {
+                    self.split_window(Split::Right);
+                    self.state = State::Default;
+                }
+                Key::Char('b') => {
+                    let prompt = format!("Switch to buffer (default {}): ", self.other_name());
+                    self.minibuffer.set_prompt(&prompt);
+                    self.minibuffer.set_completion(self.buffer_names());
+                    self.minibuffer
+                        .set_history(self.input_history.get("buffer"));
+                    self.state = State::SwitchBuffer;
+                }
+                Key::Char('u') => {
+                    self.visualize_undo_tree();
+                    self.state = State::Default;
                 }
                 Key::Char('h') => {
-                    self.buffer.mark_whole();
+                    self.buffers[current].mark_whole();
                     self.minibuffer.set_message("Mark set");
                     self.state = State::Default;
                 }
+                Key::Char('o') => {
+                    self.other_window();
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Char('k') => {
+                    let name = &self.buffers[current].name;
+                    let prompt = format!("Kill buffer (default {}): ", name);
+                    self.minibuffer.set_prompt(&prompt);
+                    self.minibuffer.set_completion(self.buffer_names());
+                    self.minibuffer
+                        .set_history(self.input_history.get("buffer"));
+                    self.state = State::KillBuffer;
+                }
                 _ => {
                     self.minibuffer.set_message("");
                     self.state = State::Default;
@@ -288,14 +867,127 @@ impl Editor {
                     self.state = State::Default;
                 }
                 Key::Ctrl(b'J' | b'M') => {
+                    self.input_history.add("file", self.minibuffer.get_input());
+                    let input = expand_path(self.minibuffer.get_input());
+                    self.save(Some(input))?;
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::MakeDir { ref file_path } => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let file_path = file_path.clone();
                     let input = self.minibuffer.get_input();
-                    self.buffer.save_as(input)?;
-                    self.minibuffer.set_message("Saved");
+                    if input.to_lowercase() == "y" {
+                        let path = file_path
+                            .as_deref()
+                            .or(self.buffers[current].file_path.as_deref())
+                            .map(Path::new);
+                        if let Some(dir) = path.and_then(|p| p.parent()) {
+                            fs::create_dir_all(dir)?;
+                        }
+                        self.save(file_path)?;
+                    } else {
+                        self.minibuffer.set_message("");
+                        self.state = State::Default;
+                    }
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::FindFile => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
                     self.state = State::Default;
                 }
+                Key::Ctrl(b'J' | b'M') => {
+                    let input = expand_path(self.minibuffer.get_input());
+                    if Path::new(&input).is_dir() {
+                        self.minibuffer.set_hint(" [Is a directory]");
+                    } else {
+                        self.input_history.add("file", self.minibuffer.get_input());
+                        self.find_file(&input);
+                        self.state = State::Default;
+                    }
+                }
                 _ => self.minibuffer.process_key(key),
             },
-            State::Quit => match key {
+            State::SwitchBuffer => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let input = self.minibuffer.get_input();
+                    let name = if input.is_empty() {
+                        self.other_name()
+                    } else {
+                        input.to_string()
+                    };
+                    self.input_history.add("buffer", &name);
+                    if let Some(index) = self.find_buffer(&name) {
+                        self.switch_buffer(index);
+                    } else {
+                        let mut buffer = Buffer::new(None, None)?;
+                        buffer.name = name;
+                        let index = self.add_buffer(buffer);
+                        self.switch_buffer(index);
+                    }
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::KillBuffer => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let input = self.minibuffer.get_input();
+                    let index = if input.is_empty() {
+                        Some(current)
+                    } else {
+                        self.find_buffer(input)
+                    };
+                    match index {
+                        Some(index) if self.buffers[index].modified() => {
+                            let name = &self.buffers[index].name;
+                            let prompt = format!("Buffer {} modified; kill anyway? (y/N): ", name);
+                            self.minibuffer.set_prompt(&prompt);
+                            self.state = State::KillModified { index };
+                        }
+                        Some(index) => {
+                            self.kill_buffer(index)?;
+                            self.minibuffer.set_message("");
+                            self.state = State::Default;
+                        }
+                        None => {
+                            self.minibuffer.set_message("No such buffer");
+                            self.state = State::Default;
+                        }
+                    }
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::KillModified { index } => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let input = self.minibuffer.get_input();
+                    if input.to_lowercase() == "y" {
+                        self.kill_buffer(index)?;
+                    }
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::Quit { index } => match key {
                 Key::Ctrl(b'G') => {
                     self.minibuffer.set_message("");
                     self.state = State::Default;
@@ -303,7 +995,24 @@ impl Editor {
                 Key::Ctrl(b'J' | b'M') => {
                     let input = self.minibuffer.get_input();
                     if input.is_empty() || input.to_lowercase() == "y" {
-                        self.state = State::Quitted;
+                        self.quit(index + 1);
+                    } else {
+                        self.switch_buffer(index);
+                        self.minibuffer.set_message("");
+                        self.state = State::Default;
+                    }
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::SaveChanged => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let input = self.minibuffer.get_input();
+                    if input.to_lowercase() == "y" {
+                        self.write_buffer(None);
                     } else {
                         self.minibuffer.set_message("");
                         self.state = State::Default;
@@ -311,14 +1020,847 @@ impl Editor {
                 }
                 _ => self.minibuffer.process_key(key),
             },
+            State::Revert { encoding } => match key {
+                Key::Ctrl(b'G') => {
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let input = self.minibuffer.get_input();
+                    self.state = State::Default;
+                    if input.to_lowercase() == "y" {
+                        self.revert_buffer(encoding);
+                    } else {
+                        self.minibuffer.set_message("");
+                    }
+                }
+                _ => self.minibuffer.process_key(key),
+            },
+            State::Recover { index } => match key {
+                Key::Ctrl(b'G') => {
+                    self.buffers[index].skip_recovery();
+                    self.minibuffer.set_message("");
+                    self.state = State::Default;
+                }
+                Key::Ctrl(b'J' | b'M') => {
+                    let input = self.minibuffer.get_input();
+                    if input.to_lowercase() == "y" {
+                        self.buffers[index].recover()?;
+                        let name = &self.buffers[index].name;
+                        let message = format!("Recovered {}; save it to keep the changes", name);
+                        self.minibuffer.set_message(&message);
+                    } else {
+                        self.buffers[index].skip_recovery();
+                        self.minibuffer.set_message("");
+                    }
+                    self.state = State::Default;
+                    self.offer_recovery(index + 1);
+                }
+                _ => self.minibuffer.process_key(key),
+            },
             State::Quitted => unreachable!(),
         }
         Ok(())
     }
 }
 
+impl Editor {
+    fn run_command(&mut self, name: &str) {
+        let current = self.current();
+
+        match name {
+            "grep-regexp" | "grep-string" => {
+                let regex = name == "grep-regexp";
+                let prompt = if regex {
+                    "Grep (regexp): "
+                } else {
+                    "Grep (string): "
+                };
+                self.minibuffer.set_prompt(prompt);
+                self.minibuffer
+                    .set_history(self.input_history.get(Self::search_kind(regex)));
+                self.state = State::GrepPattern { regex };
+            }
+            "next-error" | "previous-error" => {
+                self.state = State::Default;
+                self.next_error(name == "previous-error");
+            }
+            "occur" => {
+                self.minibuffer.set_prompt("List lines matching regexp: ");
+                self.minibuffer
+                    .set_history(self.input_history.get("regexp-search"));
+                self.state = State::Occur;
+            }
+            "query-replace" | "query-replace-regexp" | "replace-regexp" | "replace-string" => {
+                if self.buffers[current].read_only {
+                    self.minibuffer.set_message("Buffer is read-only");
+                    self.state = State::Default;
+                    return;
+                }
+                let regex = name.ends_with("regexp");
+                let query = name.starts_with("query");
+                let prompt = format!("{}: ", Self::replace_title(regex, query));
+                self.minibuffer.set_prompt(&prompt);
+                self.minibuffer
+                    .set_history(self.input_history.get("replace"));
+                self.state = State::ReplaceFrom { regex, query };
+            }
+            "revert-with-encoding" | "set-encoding" => {
+                let buffer = &self.buffers[current];
+                let revert = name == "revert-with-encoding";
+                if !revert && buffer.read_only {
+                    self.minibuffer.set_message("Buffer is read-only");
+                    self.state = State::Default;
+                    return;
+                }
+                let prompt = format!(
+                    "{} [{}]: ",
+                    if revert {
+                        "Revert with encoding"
+                    } else {
+                        "Encoding for saving"
+                    },
+                    buffer.encoding().name()
+                );
+                self.minibuffer.set_prompt(&prompt);
+                self.minibuffer
+                    .set_history(self.input_history.get("encoding"));
+                self.state = State::SetEncoding { revert };
+            }
+            "revert-buffer" => self.query_revert(None),
+            "auto-revert-mode" => {
+                self.auto_revert = !self.auto_revert;
+                let message = if self.auto_revert {
+                    "Auto-Revert mode enabled"
+                } else {
+                    "Auto-Revert mode disabled"
+                };
+                self.minibuffer.set_message(message);
+                self.state = State::Default;
+            }
+            "set-line-ending" => {
+                let buffer = &self.buffers[current];
+                if buffer.read_only {
+                    self.minibuffer.set_message("Buffer is read-only");
+                    self.state = State::Default;
+                    return;
+                }
+                let prompt = format!("Line ending (LF/CRLF) [{}]: ", buffer.line_ending().name());
+                let names = vec!["LF".to_string(), "CRLF".to_string()];
+                self.minibuffer.set_prompt(&prompt);
+                self.minibuffer.set_completion(Completion::Names(names));
+                self.state = State::SetLineEnding;
+            }
+            "redo" | "undo" | "undo-switch-branch" => {
+                self.state = State::Default;
+                let buffer = &mut self.buffers[current];
+                if buffer.read_only {
+                    self.minibuffer.set_message("Buffer is read-only");
+                    return;
+                }
+                let message = match name {
+                    "redo" => buffer.redo().into(),
+                    "undo" => buffer.undo().into(),
+                    _ => buffer.switch_undo_branch(),
+                };
+                self.minibuffer.set_message(&message);
+            }
+            "undo-tree-visualize" => {
+                self.state = State::Default;
+                self.visualize_undo_tree();
+            }
+            "visual-line-mode" => {
+                let message = if self.buffers[current].toggle_wrap() {
+                    "Visual-Line mode enabled"
+                } else {
+                    "Visual-Line mode disabled"
+                };
+                self.minibuffer.set_message(message);
+                self.state = State::Default;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn add_buffer(&mut self, mut buffer: Buffer) -> usize {
+        buffer.name = self.unique_name(&buffer.name, self.buffers.len());
+        buffer.set_kill_ring(&self.kill_ring);
+        self.buffers.push(buffer);
+        self.buffers.len() - 1
+    }
+
+    fn unique_name(&self, name: &str, index: usize) -> String {
+        let taken = |s: &str| {
+            self.buffers
+                .iter()
+                .enumerate()
+                .any(|(i, b)| i != index && b.name == s)
+        };
+        let mut unique = String::from(name);
+        let mut n = 2;
+        while taken(&unique) {
+            unique = format!("{}<{}>", name, n);
+            n += 1;
+        }
+        unique
+    }
+
+    fn find_buffer(&self, name: &str) -> Option<usize> {
+        self.buffers.iter().position(|b| b.name == name)
+    }
+
+    fn find_file_buffer(&self, file_path: &str) -> Option<usize> {
+        let path = fs::canonicalize(file_path).ok()?;
+        self.buffers.iter().position(|b| {
+            b.file_path
+                .as_deref()
+                .and_then(|s| fs::canonicalize(s).ok())
+                .as_ref()
+                == Some(&path)
+        })
+    }
+
+    fn buffer_names(&self) -> Completion {
+        Completion::Names(self.buffers.iter().map(|b| b.name.clone()).collect())
+    }
+
+    fn save(&mut self, file_path: Option<String>) -> io::Result<()> {
+        let current = self.current();
+        let path = file_path
+            .as_deref()
+            .or(self.buffers[current].file_path.as_deref())
+            .map(Path::new);
+
+        if let Some(dir) = path.and_then(|p| p.parent()) {
+            if !dir.as_os_str().is_empty() && !dir.exists() {
+                let prompt = format!(
+                    "Directory {} does not exist; create? (y/n): ",
+                    dir.display()
+                );
+                self.minibuffer.set_prompt(&prompt);
+                self.state = State::MakeDir { file_path };
+                return Ok(());
+            }
+        }
+
+        let buffer = &self.buffers[current];
+        if file_path.is_none() && buffer.changed_on_disk() {
+            let prompt = format!(
+                "{} has changed since visited or saved; save anyway? (y/n): ",
+                buffer.name
+            );
+            self.minibuffer.set_prompt(&prompt);
+            self.state = State::SaveChanged;
+            return Ok(());
+        }
+        self.write_buffer(file_path);
+        Ok(())
+    }
+
+    fn write_buffer(&mut self, file_path: Option<String>) {
+        let current = self.current();
+
+        // back up only the original of each file, before its first save
+        let buffer = &self.buffers[current];
+        let target = file_path.as_deref().or(buffer.file_path.as_deref());
+        let backup_error = match target {
+            Some(target) if file_path.is_some() || !buffer.backed_up() => {
+                backup::create(&self.config, target).err()
+            }
+            _ => None,
+        };
+
+        let result = if let Some(file_path) = file_path.as_deref() {
+            self.buffers[current].save_as(file_path)
+        } else {
+            self.buffers[current].save()
+        };
+
+        match result {
+            Ok(()) => {
+                let name = self.unique_name(&self.buffers[current].name, current);
+                self.buffers[current].name = name;
+                // a failed backup is tried again on the next save
+                let message = match backup_error {
+                    Some(e) => format!("Saved, but making a backup failed: {}", e),
+                    None => {
+                        self.buffers[current].set_backed_up();
+                        "Saved".into()
+                    }
+                };
+                self.minibuffer.set_message(&message);
+            }
+            Err(e) => {
+                let file_path = file_path.or_else(|| self.buffers[current].file_path.clone());
+                let message = format!("Error saving {}: {}", file_path.unwrap_or_default(), e);
+                self.minibuffer.set_message(&message);
+            }
+        }
+        self.state = State::Default;
+    }
+
+    fn find_file(&mut self, file_path: &str) {
+        if let Some(index) = self.find_file_buffer(file_path) {
+            self.switch_buffer(index);
+            self.minibuffer.set_message("");
+            return;
+        }
+
+        match Buffer::new(Some(file_path), None) {
+            Ok(buffer) => {
+                let message = Self::open_message(&buffer);
+                self.minibuffer.set_message(&message);
+                let index = self.add_buffer(buffer);
+                self.switch_buffer(index);
+                self.offer_recovery(index);
+            }
+            Err(e) => {
+                let message = format!("{}: {}", file_path, e);
+                self.minibuffer.set_message(&message);
+            }
+        }
+    }
+
+    fn start_search(&mut self, backward: bool, regex: bool) {
+        let kind = Self::search_kind(regex);
+        self.minibuffer.set_prompt(&self.search_prompt(regex));
+        self.minibuffer.set_history(self.input_history.get(kind));
+        self.state = State::Search { backward, regex };
+    }
+
+    fn search_kind(regex: bool) -> &'static str {
+        if regex {
+            "regexp-search"
+        } else {
+            "search"
+        }
+    }
+
+    fn search_prompt(&self, regex: bool) -> String {
+        let title = if regex { "Regexp search" } else { "Search" };
+        format!("{} ({}): ", title, self.case_mode.name())
+    }
+
+    fn update_search(&mut self, backward: bool, regex: bool) {
+        let current = self.current();
+        let input = self.minibuffer.get_input();
+
+        self.buffers[current].clear_matches(true);
+        if input.is_empty() {
+            return;
+        }
+        match Self::compile_query(input, regex, self.case_mode) {
+            Ok(re) => self.buffers[current].search(&re, regex, backward),
+            Err(e) => self.minibuffer.set_hint(&format!(" [{}]", e)),
+        }
+    }
+
+    fn compile_query(input: &str, regex: bool, case_mode: CaseMode) -> Result<Regex, String> {
+        let pattern = if regex {
+            format!("(?m){}", input)
+        } else {
+            regex::escape(input)
+        };
+        let pattern = if case_mode.ignores_case(input, regex) {
+            format!("(?i){}", pattern)
+        } else {
+            pattern
+        };
+        Regex::new(&pattern).map_err(|e| match e {
+            // the last line of a syntax error is its short description
+            regex::Error::Syntax(s) => s.lines().last().unwrap_or("").replace("error: ", ""),
+            _ => "Invalid regexp".into(),
+        })
+    }
+
+    fn replace_title(regex: bool, query: bool) -> &'static str {
+        match (regex, query) {
+            (false, false) => "Replace string",
+            (false, true) => "Query replace",
+            (true, false) => "Replace regexp",
+            (true, true) => "Query replace regexp",
+        }
+    }
+
+    fn finish_replace(&mut self) {
+        let current = self.current();
+        let count = self.buffers[current].finish_replace();
+        let message = format!(
+            "Replaced {} occurrence{}",
+            count,
+            if count == 1 { "" } else { "s" }
+        );
+        self.minibuffer.set_message(&message);
+        self.state = State::Default;
+    }
+
+    fn open_message(buffer: &Buffer) -> String {
+        if buffer.file_path.is_some() && !buffer.file_exists() {
+            "(New file)".into()
+        } else if buffer.decode_error() {
+            format!(
+                "Invalid {} data; opened read-only (try M-x revert-with-encoding)",
+                buffer.encoding().name()
+            )
+        } else {
+            "".into()
+        }
+    }
+
+    // Reverts the current buffer, asking first if it is modified
+    fn query_revert(&mut self, encoding: Option<Encoding>) {
+        let buffer = &self.buffers[self.current()];
+        if buffer.modified() {
+            let prompt = format!("Revert buffer from file {}? (y/n): ", buffer.name);
+            self.minibuffer.set_prompt(&prompt);
+            self.state = State::Revert { encoding };
+        } else {
+            self.state = State::Default;
+            self.revert_buffer(encoding);
+        }
+    }
+
+    // Reloads the current buffer in the given encoding or the file's one
+    fn revert_buffer(&mut self, encoding: Option<Encoding>) {
+        let current = self.current();
+        let buffer = &self.buffers[current];
+        if !buffer.file_exists() {
+            self.minibuffer
+                .set_message("Buffer is not visiting an existing file");
+            return;
+        }
+
+        let encoding = encoding.or(buffer.file_encoding());
+        match self.revert(current, encoding) {
+            Ok(()) => {
+                let message = Self::open_message(&self.buffers[current]);
+                self.minibuffer.set_message(&message);
+            }
+            Err(e) => {
+                let file_path = self.buffers[current].file_path.as_deref().unwrap();
+                let message = format!("{}: {}", file_path, e);
+                self.minibuffer.set_message(&message);
+            }
+        }
+    }
+
+    // Replaces a buffer with its file reloaded, keeping the cursor position
+    fn revert(&mut self, index: usize, encoding: Option<Encoding>) -> io::Result<()> {
+        let old = &self.buffers[index];
+        let file_path = old.file_path.as_deref().unwrap();
+
+        let mut buffer = Buffer::new(Some(file_path), encoding)?;
+        buffer.name = old.name.clone();
+        buffer.set_kill_ring(&self.kill_ring);
+        buffer.swap_view(&mut old.view());
+        self.buffers[index] = buffer;
+        if index == self.current() {
+            self.switch_buffer(index);
+        }
+        self.arrange();
+        Ok(())
+    }
+
+    fn other_name(&self) -> String {
+        let current = self.current();
+        let index = self
+            .history
+            .iter()
+            .rev()
+            .copied()
+            .chain(0..self.buffers.len())
+            .find(|&i| i != current)
+            .unwrap_or(current);
+        self.buffers[index].name.clone()
+    }
+
+    fn current(&self) -> usize {
+        self.windows[self.focus].buffer
+    }
+
+    fn switch_buffer(&mut self, index: usize) {
+        self.history.retain(|&i| i != index);
+        self.history.push(index);
+
+        let window = &mut self.windows[self.focus];
+        let size = Size::new(window.size.w, window.size.h.saturating_sub(1));
+        window.buffer = index;
+        self.buffers[index].resize(window.pos, size);
+    }
+
+    fn kill_buffer(&mut self, index: usize) -> io::Result<()> {
+        self.buffers.remove(index);
+        self.history.retain(|&i| i != index);
+        for i in self.history.iter_mut() {
+            if *i > index {
+                *i -= 1;
+            }
+        }
+        if self.buffers.is_empty() {
+            let buffer = Buffer::new(None, None)?;
+            self.add_buffer(buffer);
+        }
+
+        let other = self.history.last().copied().unwrap_or(0);
+        for (i, window) in self.windows.iter_mut().enumerate() {
+            if window.buffer == index {
+                window.buffer = other;
+                if i != self.focus {
+                    window.view = self.buffers[other].view();
+                }
+            } else if window.buffer > index {
+                window.buffer -= 1;
+            }
+        }
+        self.switch_buffer(self.current());
+        self.arrange();
+        Ok(())
+    }
+
+    fn list_buffers(&mut self) {
+        let mut lines = vec![(" MR Buffer               Size  File".into(), None)];
+        for (i, buffer) in self.buffers.iter().enumerate() {
+            let line = format!(
+                " {}{} {:<16} {:>8}  {}",
+                if i == self.current() { "." } else { " " },
+                if buffer.modified() { "*" } else { " " },
+                buffer.name,
+                buffer.len(),
+                buffer.file_path.as_deref().unwrap_or(""),
+            );
+            lines.push((line, Some(Link::Buffer(buffer.name.clone()))));
+        }
+
+        self.show_listing(BUFFER_LIST, lines);
+    }
+
+    fn occur(&mut self, regex: &Regex, pattern: &str) {
+        let buffer = &self.buffers[self.current()];
+        let matches = buffer.matching_lines(regex);
+        if matches.is_empty() {
+            self.minibuffer.set_message("No matches");
+            return;
+        }
+
+        let header = format!(
+            "{} matching line{} for \"{}\" in buffer: {}",
+            matches.len(),
+            if matches.len() == 1 { "" } else { "s" },
+            pattern,
+            buffer.name,
+        );
+        let mut lines = vec![(header, None)];
+        for (pos, string) in matches {
+            let line = format!("{:>6}:{}", pos.y + 1, string);
+            lines.push((line, Some(Link::Position(buffer.name.clone(), pos))));
+        }
+
+        self.show_listing(OCCUR, lines);
+        self.results = Some(OCCUR.into());
+        self.minibuffer.set_message("");
+    }
+
+    fn grep(&mut self, regex: &Regex, pattern: &str, dir: &str) {
+        let matches = grep::grep(regex, Path::new(dir));
+        if matches.is_empty() {
+            self.minibuffer.set_message("No matches");
+            return;
+        }
+
+        let header = format!(
+            "{} match{} for \"{}\" in {}",
+            matches.len(),
+            if matches.len() == 1 { "" } else { "es" },
+            pattern,
+            abbreviate_path(dir),
+        );
+        let mut lines = vec![(header, None)];
+        for m in matches {
+            let path = m.path.to_string_lossy().to_string();
+            let name = m.path.strip_prefix(dir).unwrap_or(&m.path).display();
+            let line = format!("{}:{}:{}", name, m.pos.y + 1, m.line);
+            lines.push((line, Some(Link::File(path, m.pos))));
+        }
+
+        self.show_listing(GREP, lines);
+        self.results = Some(GREP.into());
+        self.minibuffer.set_message("");
+    }
+
+    // Visits the next entry of the latest occur or grep results
+    fn next_error(&mut self, backward: bool) {
+        let index = self
+            .results
+            .as_deref()
+            .and_then(|name| self.find_buffer(name));
+        let link = match index {
+            Some(index) => self.buffers[index].next_link(backward),
+            None => {
+                self.minibuffer.set_message("No results to visit");
+                return;
+            }
+        };
+        match link {
+            Some(link) => self.follow_link(link),
+            None => self.minibuffer.set_message("No more results"),
+        }
+    }
+
+    fn visualize_undo_tree(&mut self) {
+        let buffer = &self.buffers[self.current()];
+        let name = buffer.name.clone();
+        let current = buffer.undo_state();
+
+        let mut lines = vec![(format!("Undo tree of buffer: {}", name), None)];
+        let mut y = 0;
+        for (line, index) in buffer.undo_tree_lines() {
+            if index == current {
+                y = lines.len();
+            }
+            lines.push((line, Some(Link::UndoState(name.clone(), index))));
+        }
+
+        self.show_listing(UNDO_TREE, lines);
+        let current = self.current();
+        self.buffers[current].goto(Pos::new(0, y));
+        self.minibuffer.set_message("");
+    }
+
+    // Replaces the listing buffer with the same name, if any
+    fn show_listing(&mut self, name: &str, lines: Vec<(String, Option<Link>)>) {
+        let mut buffer = Buffer::listing(name, lines);
+        let index = match self.find_buffer(name) {
+            Some(index) => {
+                buffer.set_kill_ring(&self.kill_ring);
+                self.buffers[index] = buffer;
+                index
+            }
+            None => self.add_buffer(buffer),
+        };
+        self.switch_buffer(index);
+    }
+
+    fn follow_link(&mut self, link: Link) {
+        match link {
+            Link::Buffer(name) => match self.find_buffer(&name) {
+                Some(index) => {
+                    self.switch_buffer(index);
+                    self.minibuffer.set_message("");
+                }
+                None => self.minibuffer.set_message("No such buffer"),
+            },
+            Link::File(file_path, pos) => {
+                self.find_file(&file_path);
+                let current = self.current();
+                if self.find_file_buffer(&file_path) == Some(current) {
+                    self.buffers[current].goto(pos);
+                }
+            }
+            Link::UndoState(name, state) => match self.find_buffer(&name) {
+                Some(index) if self.buffers[index].read_only => {
+                    self.minibuffer.set_message("Buffer is read-only");
+                }
+                Some(index) => {
+                    self.switch_buffer(index);
+                    self.buffers[index].goto_undo_state(state);
+                    self.minibuffer.set_message("");
+                }
+                None => self.minibuffer.set_message("No such buffer"),
+            },
+            Link::Position(name, pos) => match self.find_buffer(&name) {
+                Some(index) => {
+                    self.switch_buffer(index);
+                    self.buffers[index].goto(pos);
+                    self.minibuffer.set_message("");
+                }
+                None => self.minibuffer.set_message("No such buffer"),
+            },
+        }
+    }
+
+    fn arrange(&mut self) {
+        let Size { w, h } = self.screen_size;
+        let size = Size::new(w, h.saturating_sub(1));
+
+        self.borders.clear();
+        self.layout
+            .arrange(Pos::new(0, 0), size, &mut self.windows, &mut self.borders);
+
+        for (i, window) in self.windows.iter_mut().enumerate() {
+            let buffer = &mut self.buffers[window.buffer];
+            let size = Size::new(window.size.w, window.size.h.saturating_sub(1));
+            if i == self.focus {
+                buffer.resize(window.pos, size);
+            } else {
+                buffer.swap_view(&mut window.view);
+                buffer.resize(window.pos, size);
+                buffer.swap_view(&mut window.view);
+            }
+        }
+    }
+
+    fn sync_windows(&mut self) {
+        let current = self.current();
+        if self.buffers[current].needs_draw() {
+            for (i, window) in self.windows.iter_mut().enumerate() {
+                if i != self.focus && window.buffer == current {
+                    window.view.redraw();
+                }
+            }
+        }
+    }
+
+    fn focus_window(&mut self, index: usize) {
+        let window = &mut self.windows[self.focus];
+        window.view = self.buffers[window.buffer].view();
+
+        self.focus = index;
+        let window = &self.windows[index];
+        let mut view = window.view.clone();
+        self.buffers[window.buffer].swap_view(&mut view);
+
+        let current = self.current();
+        self.history.retain(|&i| i != current);
+        self.history.push(current);
+    }
+
+    fn split_window(&mut self, split: Split) {
+        if !self.windows[self.focus].can_split(split) {
+            self.minibuffer
+                .set_message("Window too small for splitting");
+            return;
+        }
+
+        let current = self.current();
+        let mut window = Window::new(current);
+        window.view = self.buffers[current].view();
+
+        self.windows.insert(self.focus + 1, window);
+        self.layout.split(self.focus, split);
+        self.arrange();
+        self.minibuffer.set_message("");
+    }
+
+    fn other_window(&mut self) {
+        let index = (self.focus + 1) % self.windows.len();
+        self.focus_window(index);
+    }
+
+    fn delete_window(&mut self) {
+        if self.windows.len() == 1 {
+            self.minibuffer
+                .set_message("Attempt to delete sole ordinary window");
+            return;
+        }
+
+        self.windows.remove(self.focus);
+        self.layout.remove(self.focus);
+        self.focus = self.focus.saturating_sub(1);
+
+        let window = &self.windows[self.focus];
+        let mut view = window.view.clone();
+        self.buffers[window.buffer].swap_view(&mut view);
+
+        self.arrange();
+        self.minibuffer.set_message("");
+    }
+
+    fn delete_other_windows(&mut self) {
+        let window = self.windows.swap_remove(self.focus);
+        self.windows = vec![window];
+        self.layout = Layout::Leaf(0);
+        self.focus = 0;
+        self.arrange();
+    }
+
+    // Asks whether to recover each buffer with a newer auto-save file in turn
+    fn offer_recovery(&mut self, start: usize) {
+        let index = (start..self.buffers.len()).find(|&i| self.buffers[i].recoverable());
+
+        if let Some(index) = index {
+            self.switch_buffer(index);
+            let file_path = self.buffers[index].file_path.as_deref().unwrap();
+            let prompt = format!(
+                "Recover auto-save file {}? (y/n): ",
+                abbreviate_path(&auto_save::path(file_path).to_string_lossy())
+            );
+            self.minibuffer.set_prompt(&prompt);
+            self.state = State::Recover { index };
+        }
+    }
+
+    // Highlights the buffers little by little while no key is pressed
+    fn highlight_more(&mut self) {
+        let current = self.current();
+        let index = if self.buffers[current].highlighted_all() {
+            self.buffers.iter().position(|b| !b.highlighted_all())
+        } else {
+            Some(current)
+        };
+        if let Some(index) = index {
+            self.buffers[index].highlight_more();
+        }
+    }
+
+    fn auto_save_due(&self) -> bool {
+        self.keystrokes >= AUTO_SAVE_INTERVAL || self.auto_saved_at.elapsed() >= AUTO_SAVE_TIMEOUT
+    }
+
+    fn auto_save(&mut self) {
+        for buffer in self.buffers.iter_mut() {
+            if let Err(e) = buffer.auto_save() {
+                let message = format!("Auto-saving {} failed: {}", buffer.name, e);
+                self.minibuffer.set_message(&message);
+            }
+        }
+        self.keystrokes = 0;
+        self.auto_saved_at = Instant::now();
+    }
+
+    fn auto_revert_due(&self) -> bool {
+        self.auto_revert && self.auto_reverted_at.elapsed() >= AUTO_REVERT_INTERVAL
+    }
+
+    // Reverts the unmodified buffers whose files changed on disk,
+    // unless a prompt may be working on one of them
+    fn auto_revert(&mut self) {
+        self.auto_reverted_at = Instant::now();
+        if self.state != State::Default {
+            return;
+        }
+
+        for index in 0..self.buffers.len() {
+            let buffer = &self.buffers[index];
+            if buffer.modified() || !buffer.file_exists() || !buffer.changed_on_disk() {
+                continue;
+            }
+            let encoding = buffer.file_encoding();
+            let message = match self.revert(index, encoding) {
+                Ok(()) => format!("Reverted buffer {}", self.buffers[index].name),
+                Err(e) => format!("Reverting {} failed: {}", self.buffers[index].name, e),
+            };
+            self.minibuffer.set_message(&message);
+        }
+    }
+
+    fn quit(&mut self, start: usize) {
+        let index = (start..self.buffers.len()).find(|&i| self.buffers[i].modified());
+
+        if let Some(index) = index {
+            let name = &self.buffers[index].name;
+            let prompt = format!("Quit without saving changes to {}? (Y/n): ", name);
+            self.minibuffer.set_prompt(&prompt);
+            self.state = State::Quit { index };
+        } else {
+            self.state = State::Quitted;
+        }
+    }
+}
+
 impl Drop for Editor {
     fn drop(&mut self) {
+        self.input_history.save().unwrap_or(());
+
         // switch to main screen buffer
         self.stdout.write(b"\x1b[?1049l").unwrap();
         self.stdout.flush().unwrap();
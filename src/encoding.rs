@@ -0,0 +1,124 @@
+use chardetng::EncodingDetector;
+use encoding_rs::{CoderResult, UTF_16BE, UTF_16LE, UTF_8};
+use std::borrow::Cow;
+use std::io::{self, ErrorKind};
+use std::str;
+
+// Bytes of text decoded at a time
+const DECODE_CHUNK_LEN: usize = 1 << 16;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Encoding {
+    inner: &'static encoding_rs::Encoding,
+    bom: bool,
+}
+
+impl Encoding {
+    pub fn for_label(label: &str) -> Option<Self> {
+        let label = label.trim().to_lowercase();
+        let (label, bom) = match label.strip_suffix("-bom") {
+            Some(label) => (label, true),
+            None => (label.as_str(), false),
+        };
+        let inner = encoding_rs::Encoding::for_label(label.as_bytes())?;
+        Some(Self { inner, bom })
+    }
+
+    // Returns the encoding and the length of the BOM
+    pub fn detect(bytes: &[u8]) -> (Self, usize) {
+        if let Some((inner, len)) = encoding_rs::Encoding::for_bom(bytes) {
+            return (Self { inner, bom: true }, len);
+        }
+        if str::from_utf8(bytes).is_ok() {
+            return (Default::default(), 0);
+        }
+        let mut detector = EncodingDetector::new();
+        detector.feed(bytes, true);
+        let inner = detector.guess(None, true);
+        (Self { inner, bom: false }, 0)
+    }
+
+    pub fn name(&self) -> String {
+        if self.bom {
+            format!("{}-BOM", self.inner.name())
+        } else {
+            self.inner.name().to_string()
+        }
+    }
+
+    // Passes the decoded text to f a chunk at a time, so that the whole of
+    // it is never held at once, and returns whether malformed sequences were
+    // replaced
+    pub fn decode(&self, bytes: &[u8], mut f: impl FnMut(&str)) -> bool {
+        let mut decoder = self.inner.new_decoder_without_bom_handling();
+        let mut chunk = String::with_capacity(DECODE_CHUNK_LEN);
+        let mut replaced = false;
+        let mut start = 0;
+        loop {
+            let (result, read, chunk_replaced) =
+                decoder.decode_to_string(&bytes[start..], &mut chunk, true);
+            start += read;
+            replaced |= chunk_replaced;
+            f(&chunk);
+            chunk.clear();
+            if result == CoderResult::InputEmpty {
+                return replaced;
+            }
+        }
+    }
+
+    pub fn encode<'a>(&self, text: &'a str) -> io::Result<Cow<'a, [u8]>> {
+        let mut bytes = if self.inner == UTF_16LE {
+            Cow::Owned(text.encode_utf16().flat_map(u16::to_le_bytes).collect())
+        } else if self.inner == UTF_16BE {
+            Cow::Owned(text.encode_utf16().flat_map(u16::to_be_bytes).collect())
+        } else {
+            let (bytes, _, unmappable) = self.inner.encode(text);
+            if unmappable {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Text cannot be encoded in {}", self.name()),
+                ));
+            }
+            bytes
+        };
+        if self.bom {
+            let mut with_bom = self.bom_bytes().to_vec();
+            with_bom.extend_from_slice(&bytes);
+            bytes = Cow::Owned(with_bom);
+        }
+        Ok(bytes)
+    }
+
+    // Returns the encoding marked with a BOM if the bytes start with its
+    // BOM, and the length of the BOM
+    pub fn detect_bom(self, bytes: &[u8]) -> (Self, usize) {
+        let bom = self.bom_bytes();
+        if !bom.is_empty() && bytes.starts_with(bom) {
+            (Self { bom: true, ..self }, bom.len())
+        } else {
+            (self, 0)
+        }
+    }
+
+    fn bom_bytes(&self) -> &'static [u8] {
+        if self.inner == UTF_8 {
+            b"\xef\xbb\xbf"
+        } else if self.inner == UTF_16LE {
+            b"\xff\xfe"
+        } else if self.inner == UTF_16BE {
+            b"\xfe\xff"
+        } else {
+            b""
+        }
+    }
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Self {
+            inner: UTF_8,
+            bom: false,
+        }
+    }
+}
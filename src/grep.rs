@@ -0,0 +1,63 @@
+use ignore::WalkBuilder;
+use regex::Regex;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::coord::Pos;
+use crate::row::Row;
+
+const BINARY_CHECK_LEN: usize = 8192;
+
+pub struct GrepMatch {
+    pub path: PathBuf,
+    pub pos: Pos,
+    pub line: String,
+}
+
+// Searches the files under the directory, skipping ignored and binary files
+pub fn grep(regex: &Regex, dir: &Path) -> Vec<GrepMatch> {
+    let mut matches = Vec::new();
+    let walker = WalkBuilder::new(dir)
+        .require_git(false)
+        .sort_by_file_path(|a, b| a.cmp(b))
+        .build();
+
+    for entry in walker.flatten() {
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let bytes = match fs::read(entry.path()) {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+        if bytes[..bytes.len().min(BINARY_CHECK_LEN)].contains(&0) {
+            continue;
+        }
+
+        let text = String::from_utf8_lossy(&bytes);
+        for (y, line) in text.lines().enumerate() {
+            if let Some(m) = regex.find_iter(line).find(|m| !m.is_empty()) {
+                let x = Row::new(line).idx_to_x(m.start());
+                matches.push(GrepMatch {
+                    path: entry.path().to_path_buf(),
+                    pos: Pos::new(x, y),
+                    line: line.to_string(),
+                });
+            }
+        }
+    }
+    matches
+}
+
+// The nearest ancestor with a version control directory, or the start itself
+pub fn project_root(start: &Path) -> PathBuf {
+    start
+        .ancestors()
+        .find(|dir| {
+            [".git", ".hg", ".svn"]
+                .iter()
+                .any(|vcs| dir.join(vcs).exists())
+        })
+        .unwrap_or(start)
+        .to_path_buf()
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::util::{data_dir, escape, unescape, write_atomic};
+
+const MAX_ENTRIES: usize = 100;
+
+// Minibuffer inputs for each kind of prompt, ordered from oldest to newest
+pub struct History {
+    lists: HashMap<String, Vec<String>>,
+}
+
+impl History {
+    pub fn load() -> Self {
+        let mut lists: HashMap<String, Vec<String>> = HashMap::new();
+        let string = Self::path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .unwrap_or_default();
+
+        for line in string.lines() {
+            if let Some((kind, entry)) = line.split_once('\t') {
+                lists.entry(kind.into()).or_default().push(unescape(entry));
+            }
+        }
+        Self { lists }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = match Self::path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        fs::create_dir_all(path.parent().unwrap())?;
+
+        let mut kinds: Vec<_> = self.lists.keys().collect();
+        kinds.sort();
+
+        write_atomic(&path, |writer| {
+            for kind in kinds {
+                for entry in &self.lists[kind] {
+                    writeln!(writer, "{}\t{}", kind, escape(entry))?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    pub fn get(&self, kind: &str) -> Vec<String> {
+        self.lists.get(kind).cloned().unwrap_or_default()
+    }
+
+    pub fn last(&self, kind: &str) -> Option<&str> {
+        self.lists.get(kind)?.last().map(|s| s.as_str())
+    }
+
+    pub fn add(&mut self, kind: &str, entry: &str) {
+        if entry.is_empty() {
+            return;
+        }
+        let list = self.lists.entry(kind.into()).or_default();
+        list.retain(|s| s != entry);
+        list.push(entry.into());
+        if list.len() > MAX_ENTRIES {
+            list.remove(0);
+        }
+    }
+
+    fn path() -> Option<PathBuf> {
+        Some(data_dir()?.join("history"))
+    }
+}
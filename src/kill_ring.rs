@@ -0,0 +1,68 @@
+use crate::clipboard::{Clipboard, Internal};
+
+const MAX_ENTRIES: usize = 120;
+
+// Entries are ordered from oldest to newest
+pub struct KillRing {
+    entries: Vec<String>,
+    index: usize,
+    clipboard: Box<dyn Clipboard>,
+}
+
+impl KillRing {
+    pub fn new(clipboard: Box<dyn Clipboard>) -> Self {
+        Self {
+            entries: Vec::new(),
+            index: 0,
+            clipboard,
+        }
+    }
+
+    pub fn push(&mut self, string: String) {
+        if self.entries.len() == MAX_ENTRIES {
+            self.entries.remove(0);
+        }
+        self.entries.push(string);
+        self.sync_clipboard();
+    }
+
+    pub fn append(&mut self, string: &str, prepend: bool) {
+        match self.entries.last_mut() {
+            Some(last) if prepend => last.insert_str(0, string),
+            Some(last) => last.push_str(string),
+            None => self.entries.push(string.into()),
+        }
+        self.sync_clipboard();
+    }
+
+    pub fn yank(&mut self) -> Option<String> {
+        if let Some(contents) = self.clipboard.get() {
+            // something was copied outside the editor
+            if !contents.is_empty() && self.entries.last() != Some(&contents) {
+                self.push(contents);
+            }
+        }
+        self.index = self.entries.len().checked_sub(1)?;
+        Some(self.entries[self.index].clone())
+    }
+
+    pub fn yank_pop(&mut self) -> Option<String> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        self.index = self.index.checked_sub(1).unwrap_or(self.entries.len() - 1);
+        Some(self.entries[self.index].clone())
+    }
+
+    fn sync_clipboard(&mut self) {
+        if let Some(last) = self.entries.last() {
+            self.clipboard.set(last);
+        }
+    }
+}
+
+impl Default for KillRing {
+    fn default() -> Self {
+        Self::new(Box::new(Internal))
+    }
+}
@@ -0,0 +1,432 @@
+use std::cell::OnceCell;
+use std::iter;
+use std::ops::Range;
+use std::slice;
+
+// Lines are grouped into chunks of about this many
+const CHUNK_LEN: usize = 512;
+
+type Tree<T> = Option<Box<Node<T>>>;
+
+// A treap of chunks of lines in their order
+struct Node<T> {
+    len: usize,
+    // the values of the lines, allocated on first access
+    values: OnceCell<Vec<T>>,
+    // the number of lines in this subtree
+    count: usize,
+    priority: u32,
+    left: Tree<T>,
+    right: Tree<T>,
+}
+
+impl<T> Node<T> {
+    fn new(len: usize, values: OnceCell<Vec<T>>, priority: u32) -> Self {
+        Self {
+            len,
+            values,
+            count: len,
+            priority,
+            left: None,
+            right: None,
+        }
+    }
+
+    fn update(&mut self) {
+        self.count = count(&self.left) + self.len + count(&self.right);
+    }
+}
+
+fn count<T>(tree: &Tree<T>) -> usize {
+    tree.as_ref().map_or(0, |node| node.count)
+}
+
+fn values<T: Default>(cell: &OnceCell<Vec<T>>, len: usize) -> &Vec<T> {
+    cell.get_or_init(|| iter::repeat_with(T::default).take(len).collect())
+}
+
+fn values_mut<T: Default>(cell: &mut OnceCell<Vec<T>>, len: usize) -> &mut Vec<T> {
+    values(cell, len);
+    cell.get_mut().unwrap()
+}
+
+fn merge<T>(tree1: Tree<T>, tree2: Tree<T>) -> Tree<T> {
+    match (tree1, tree2) {
+        (None, tree) | (tree, None) => tree,
+        (Some(mut node1), Some(mut node2)) => {
+            if node1.priority > node2.priority {
+                node1.right = merge(node1.right.take(), Some(node2));
+                node1.update();
+                Some(node1)
+            } else {
+                node2.left = merge(Some(node1), node2.left.take());
+                node2.update();
+                Some(node2)
+            }
+        }
+    }
+}
+
+// Splits a tree into its first n lines and the rest, cutting a chunk in two
+// if needed
+fn split<T>(tree: Tree<T>, n: usize) -> (Tree<T>, Tree<T>) {
+    let mut node = match tree {
+        Some(node) => node,
+        None => return (None, None),
+    };
+    let left_count = count(&node.left);
+
+    if n <= left_count {
+        let (tree1, tree2) = split(node.left.take(), n);
+        node.left = tree2;
+        node.update();
+        (tree1, Some(node))
+    } else if n >= left_count + node.len {
+        let (tree1, tree2) = split(node.right.take(), n - left_count - node.len);
+        node.right = tree1;
+        node.update();
+        (Some(node), tree2)
+    } else {
+        // the tail keeps the priority, which is still no lower than those
+        // of its children
+        let offset = n - left_count;
+        let values = match node.values.get_mut() {
+            Some(values) => OnceCell::from(values.split_off(offset)),
+            None => OnceCell::new(),
+        };
+        let mut tail = Box::new(Node::new(node.len - offset, values, node.priority));
+        tail.right = node.right.take();
+        tail.update();
+        node.len = offset;
+        node.update();
+        (Some(node), Some(tail))
+    }
+}
+
+// Holds a value for each line, where lines can be inserted and removed in
+// O(log n) time plus the length of a chunk. Chunks are allocated only when
+// their values are accessed.
+pub struct LineTree<T> {
+    root: Tree<T>,
+    seed: u32,
+}
+
+impl<T: Default> LineTree<T> {
+    pub fn new(len: usize) -> Self {
+        let mut tree = Self {
+            root: None,
+            seed: 0x9e37_79b9,
+        };
+        tree.root = tree.build(len, None);
+        tree
+    }
+
+    pub fn len(&self) -> usize {
+        count(&self.root)
+    }
+
+    pub fn get(&self, y: usize) -> &T {
+        let (node, offset) = self.seek(y);
+        &values(&node.values, node.len)[offset]
+    }
+
+    pub fn get_mut(&mut self, y: usize) -> &mut T {
+        let mut tree = self.root.as_mut();
+        let mut y = y;
+
+        while let Some(node) = tree {
+            let left_count = count(&node.left);
+            if y < left_count {
+                tree = node.left.as_mut();
+            } else if y < left_count + node.len {
+                return &mut values_mut(&mut node.values, node.len)[y - left_count];
+            } else {
+                y -= left_count + node.len;
+                tree = node.right.as_mut();
+            }
+        }
+        panic!("line out of range");
+    }
+
+    // Replaces `removed` lines at `y` with `inserted` ones with default values
+    pub fn splice(&mut self, y: usize, removed: usize, inserted: usize) {
+        let (tree1, tree2) = split(self.root.take(), y);
+        let (_, tree3) = split(tree2, removed);
+
+        // join the chunks on both sides rather than leave them cut
+        let mut last = tree1.as_ref();
+        while let Some(node) = last.and_then(|node| node.right.as_ref()) {
+            last = Some(node);
+        }
+        let last_len = last.map_or(0, |node| node.len);
+        let mut first = tree3.as_ref();
+        while let Some(node) = first.and_then(|node| node.left.as_ref()) {
+            first = Some(node);
+        }
+        let first_len = first.map_or(0, |node| node.len);
+
+        let count1 = count(&tree1);
+        let (tree1, last) = split(tree1, count1 - last_len);
+        let (first, tree3) = split(tree3, first_len);
+
+        let len = last_len + inserted + first_len;
+        let values = match (last, first) {
+            (Some(mut last), Some(mut first))
+                if last.values.get().is_some() || first.values.get().is_some() =>
+            {
+                let mut values = values_mut(&mut last.values, last_len).split_off(0);
+                values.extend(iter::repeat_with(T::default).take(inserted));
+                values.append(values_mut(&mut first.values, first_len));
+                Some(values)
+            }
+            (Some(mut last), None) if last.values.get().is_some() => {
+                let mut values = last.values.take().unwrap();
+                values.extend(iter::repeat_with(T::default).take(inserted));
+                Some(values)
+            }
+            (None, Some(mut first)) if first.values.get().is_some() => {
+                let mut values = first.values.take().unwrap();
+                values.splice(0..0, iter::repeat_with(T::default).take(inserted));
+                Some(values)
+            }
+            _ => None,
+        };
+        let tree2 = self.build(len, values);
+        self.root = merge(merge(tree1, tree2), tree3);
+    }
+
+    // Iterates over the values of the lines in the range
+    pub fn iter_mut(&mut self, range: Range<usize>) -> IterMut<'_, T> {
+        let mut iter = IterMut {
+            stack: Vec::new(),
+            values: [].iter_mut(),
+            remaining: range.len(),
+        };
+        let mut tree = self.root.as_deref_mut();
+        let mut y = range.start;
+
+        while let Some(node) = tree {
+            let left_count = count(&node.left);
+            let Node {
+                len,
+                values,
+                left,
+                right,
+                ..
+            } = node;
+
+            if y < left_count {
+                iter.push(right.as_deref_mut());
+                iter.stack.push(Step::Values(values, *len));
+                tree = left.as_deref_mut();
+            } else if y < left_count + *len {
+                iter.push(right.as_deref_mut());
+                iter.values = values_mut(values, *len)[(y - left_count)..].iter_mut();
+                break;
+            } else {
+                y -= left_count + *len;
+                tree = right.as_deref_mut();
+            }
+        }
+        iter
+    }
+
+    // Calls the function with the first line and the values of each chunk
+    // allocated, dropping the values of those it returns false for
+    pub fn retain_chunks<F>(&mut self, mut f: F)
+    where
+        F: FnMut(usize, &mut [T]) -> bool,
+    {
+        fn visit<T, F>(tree: &mut Tree<T>, start: usize, f: &mut F)
+        where
+            F: FnMut(usize, &mut [T]) -> bool,
+        {
+            if let Some(node) = tree {
+                let left_count = count(&node.left);
+                visit(&mut node.left, start, f);
+                if let Some(values) = node.values.get_mut() {
+                    if !f(start + left_count, values) {
+                        node.values = OnceCell::new();
+                    }
+                }
+                visit(&mut node.right, start + left_count + node.len, f);
+            }
+        }
+        visit(&mut self.root, 0, &mut f);
+    }
+
+    fn seek(&self, mut y: usize) -> (&Node<T>, usize) {
+        let mut tree = self.root.as_ref();
+
+        while let Some(node) = tree {
+            let left_count = count(&node.left);
+            if y < left_count {
+                tree = node.left.as_ref();
+            } else if y < left_count + node.len {
+                return (node, y - left_count);
+            } else {
+                y -= left_count + node.len;
+                tree = node.right.as_ref();
+            }
+        }
+        panic!("line out of range");
+    }
+
+    // Splits lines into chunks of similar lengths
+    fn build(&mut self, len: usize, mut values: Option<Vec<T>>) -> Tree<T> {
+        let count = len.div_ceil(CHUNK_LEN);
+        let mut tree = None;
+
+        // from the end, so that splitting off the values takes no copying of
+        // those before
+        for i in (0..count).rev() {
+            let start = len * i / count;
+            let end = len * (i + 1) / count;
+            let values = match values.as_mut() {
+                Some(values) => OnceCell::from(values.split_off(start)),
+                None => OnceCell::new(),
+            };
+            let node = Node::new(end - start, values, self.next_priority());
+            tree = merge(Some(Box::new(node)), tree);
+        }
+        tree
+    }
+
+    // xorshift32
+    fn next_priority(&mut self) -> u32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed
+    }
+}
+
+enum Step<'a, T> {
+    Values(&'a mut OnceCell<Vec<T>>, usize),
+    Tree(&'a mut Node<T>),
+}
+
+// Goes through the chunks in order with a stack of those to come, so that
+// only the chunks reached are allocated
+pub struct IterMut<'a, T> {
+    stack: Vec<Step<'a, T>>,
+    values: slice::IterMut<'a, T>,
+    remaining: usize,
+}
+
+impl<'a, T: Default> IterMut<'a, T> {
+    fn push(&mut self, tree: Option<&'a mut Node<T>>) {
+        if let Some(node) = tree {
+            self.stack.push(Step::Tree(node));
+        }
+    }
+}
+
+impl<'a, T: Default> Iterator for IterMut<'a, T> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<&'a mut T> {
+        if self.remaining == 0 {
+            return None;
+        }
+        loop {
+            if let Some(value) = self.values.next() {
+                self.remaining -= 1;
+                return Some(value);
+            }
+            match self.stack.pop()? {
+                Step::Values(values, len) => self.values = values_mut(values, len).iter_mut(),
+                Step::Tree(node) => {
+                    let Node {
+                        len,
+                        values,
+                        left,
+                        right,
+                        ..
+                    } = node;
+                    self.push(right.as_deref_mut());
+                    self.stack.push(Step::Values(values, *len));
+                    self.push(left.as_deref_mut());
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T: Default> ExactSizeIterator for IterMut<'_, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 as usize % n
+        }
+    }
+
+    fn check(tree: &mut LineTree<usize>, lines: &[usize]) {
+        assert_eq!(tree.len(), lines.len());
+        for (y, &n) in lines.iter().enumerate() {
+            assert_eq!(*tree.get(y), n, "line {}", y);
+        }
+        let start = lines.len() / 3;
+        let values: Vec<_> = tree.iter_mut(start..lines.len()).map(|n| *n).collect();
+        assert_eq!(values, lines[start..]);
+    }
+
+    // Lines are spliced at random, a few at a time or many, and some are set
+    // to distinct numbers, leaving most chunks unallocated
+    #[test]
+    fn random_splices() {
+        let mut rng = Rng(0x2545_f491);
+        let mut tree = LineTree::new(3000);
+        let mut lines = vec![0; 3000];
+        let mut next = 1;
+
+        for i in 0..1000 {
+            let max = if i % 10 == 0 { 2000 } else { 5 };
+            let y = rng.next(lines.len() + 1);
+            let removed = rng.next((lines.len() - y).min(max) + 1);
+            let inserted = rng.next(max);
+            tree.splice(y, removed, inserted);
+            lines.splice(y..(y + removed), iter::repeat_n(0, inserted));
+
+            for _ in 0..3 {
+                if !lines.is_empty() {
+                    let y = rng.next(lines.len());
+                    *tree.get_mut(y) = next;
+                    lines[y] = next;
+                    next += 1;
+                }
+            }
+            check(&mut tree, &lines);
+        }
+    }
+
+    #[test]
+    fn retain_chunks() {
+        let mut tree = LineTree::new(5000);
+        for y in (0..5000).step_by(7) {
+            *tree.get_mut(y) = y;
+        }
+        tree.retain_chunks(|start, values| {
+            for (i, n) in values.iter_mut().enumerate() {
+                assert!(*n == 0 || *n == start + i);
+                *n = 0;
+            }
+            start > 0
+        });
+        let lines = vec![0; 5000];
+        check(&mut tree, &lines);
+    }
+}
@@ -0,0 +1,9 @@
+use crate::coord::Pos;
+
+#[derive(Clone)]
+pub enum Link {
+    Buffer(String),
+    Position(String, Pos),
+    File(String, Pos),
+    UndoState(String, usize),
+}
@@ -3,32 +3,78 @@
 #[macro_use]
 mod color;
 
+mod auto_save;
+mod backup;
 mod buffer;
 mod canvas;
+mod clipboard;
+mod column_map;
+mod completion;
+mod config;
 mod coord;
 mod edit;
 mod editor;
+mod encoding;
 mod face;
+mod grep;
+mod history;
 mod key;
+mod kill_ring;
+mod line_ending;
+mod line_tree;
+mod link;
 mod minibuffer;
 mod raw_mode;
 mod row;
 mod rows;
 mod syntax;
+mod undo_file;
+mod undo_tree;
 mod util;
+mod window;
 
 use std::env;
 use std::io;
+use std::process;
 
 use crate::editor::Editor;
+use crate::encoding::Encoding;
 use crate::raw_mode::RawMode;
 
 fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+    let mut args = env::args().skip(1);
+    let mut file_paths = Vec::new();
+    let mut encoding = None;
+
+    while let Some(arg) = args.next() {
+        let label = if arg == "-e" || arg == "--encoding" {
+            args.next().unwrap_or_default()
+        } else if let Some(label) = arg.strip_prefix("--encoding=") {
+            label.to_string()
+        } else {
+            file_paths.push(arg);
+            continue;
+        };
+        match Encoding::for_label(&label) {
+            Some(e) => encoding = Some(e),
+            None => {
+                let message = format!("unknown encoding: {}", label);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+        }
+    }
 
     let raw_mode = RawMode::new()?;
     raw_mode.enable()?;
 
-    let mut editor = Editor::new(args.get(1).map(|s| s.as_str()))?;
-    editor.run()
+    let mut editor = Editor::new(&file_paths, encoding)?;
+    editor.run()?;
+
+    // exit as the signal would have, once the terminal is restored
+    if let Some(signal) = editor.terminated_by() {
+        drop(editor);
+        drop(raw_mode);
+        process::exit(128 + signal);
+    }
+    Ok(())
 }
@@ -1,6 +1,7 @@
 use std::io::{self, Write};
 
 use crate::canvas::Canvas;
+use crate::completion::{self, Completion};
 use crate::coord::{Pos, Size};
 use crate::face::{Bg, Fg};
 use crate::key::Key;
@@ -12,6 +13,11 @@ pub struct Minibuffer {
     offset: usize,
     cursor: usize,
     prompt_len: usize,
+    hint_x: Option<usize>,
+    completion: Option<Completion>,
+    history: Vec<String>,
+    history_index: Option<usize>,
+    orig_input: String,
     row: Row,
     draw: bool,
 }
@@ -24,18 +30,25 @@ impl Minibuffer {
             offset: 0,
             cursor: 0,
             prompt_len: 0,
+            hint_x: None,
+            completion: None,
+            history: Vec::new(),
+            history_index: None,
+            orig_input: String::new(),
             row: Row::new(""),
             draw: true,
         }
     }
 
     pub fn set_message(&mut self, string: &str) {
+        self.completion = None;
         if string != self.row.string {
             self.row.clear();
             self.row.push_str(string);
             self.offset = 0;
             self.cursor = 0;
             self.prompt_len = 0;
+            self.hint_x = None;
             self.highlight();
         }
     }
@@ -46,11 +59,43 @@ impl Minibuffer {
         self.offset = 0;
         self.cursor = self.row.last_x();
         self.prompt_len = self.row.last_x();
+        self.hint_x = None;
+        self.completion = None;
+        self.history.clear();
+        self.history_index = None;
         self.highlight();
     }
 
+    pub fn set_input(&mut self, string: &str) {
+        self.clear_hint();
+        self.row.truncate(self.prompt_len);
+        self.row.push_str(string);
+        self.cursor = self.row.last_x();
+        self.highlight();
+        self.scroll();
+    }
+
+    pub fn set_hint(&mut self, string: &str) {
+        self.clear_hint();
+        if !string.is_empty() {
+            self.hint_x = Some(self.row.last_x());
+            self.row.push_str(string);
+            self.highlight();
+        }
+    }
+
+    pub fn set_completion(&mut self, completion: Completion) {
+        self.completion = Some(completion);
+    }
+
+    pub fn set_history(&mut self, history: Vec<String>) {
+        self.history = history;
+        self.history_index = None;
+    }
+
     pub fn get_input(&self) -> &str {
-        self.row.read_str(self.prompt_len, self.row.last_x())
+        let x = self.hint_x.unwrap_or_else(|| self.row.last_x());
+        self.row.read_str(self.prompt_len, x)
     }
 
     pub fn resize(&mut self, pos: Pos, size: Size) {
@@ -65,7 +110,7 @@ impl Minibuffer {
             let x_range = self.offset..(self.offset + self.size.w);
 
             canvas.set_cursor(self.pos.x, self.pos.y)?;
-            self.row.draw(canvas, x_range)?;
+            self.row.draw(canvas, x_range, &[])?;
             canvas.write(b"\x1b[K")?;
 
             self.draw = false;
@@ -78,6 +123,8 @@ impl Minibuffer {
     }
 
     pub fn process_key(&mut self, key: Key) {
+        self.clear_hint();
+
         match key {
             Key::ArrowLeft | Key::Ctrl(b'B') => {
                 if let Some(x) = self.row.prev_x(self.cursor) {
@@ -121,6 +168,15 @@ impl Minibuffer {
                     }
                 }
             }
+            Key::ArrowUp | Key::Alt(b'p') => {
+                self.step_history(true);
+            }
+            Key::ArrowDown | Key::Alt(b'n') => {
+                self.step_history(false);
+            }
+            Key::Ctrl(b'I') if self.completion.is_some() => {
+                self.complete();
+            }
             Key::Ctrl(b'I') => {
                 if self.cursor >= self.prompt_len {
                     let x = self.row.insert_str(self.cursor, "\t");
@@ -155,14 +211,78 @@ impl Minibuffer {
         }
     }
 
+    fn step_history(&mut self, older: bool) {
+        let len = self.history.len();
+        let index = match (self.history_index, older) {
+            (None, true) if len > 0 => Some(len - 1),
+            (Some(i), true) if i > 0 => Some(i - 1),
+            (Some(i), false) if i + 1 < len => Some(i + 1),
+            (Some(_), false) => None,
+            (None, _) | (Some(_), true) => {
+                let edge = if older {
+                    " [Beginning of history]"
+                } else {
+                    " [End of history]"
+                };
+                self.set_hint(edge);
+                return;
+            }
+        };
+
+        if self.history_index.is_none() {
+            self.orig_input = self.get_input().to_string();
+        }
+        self.history_index = index;
+        let input = match index {
+            Some(i) => self.history[i].clone(),
+            None => self.orig_input.clone(),
+        };
+        self.set_input(&input);
+    }
+
+    fn complete(&mut self) {
+        let input = self.get_input().to_string();
+        let candidates = match self.completion.as_ref() {
+            Some(completion) => completion.candidates(&input),
+            None => return,
+        };
+        let prefix = completion::common_prefix(&candidates);
+
+        if candidates.is_empty() {
+            self.set_hint(" [No match]");
+        } else if prefix.len() > input.len() {
+            let prefix = prefix.to_string();
+            self.set_input(&prefix);
+        } else if candidates.len() == 1 {
+            self.set_hint(" [Sole completion]");
+        } else {
+            let dir_len = input.rfind('/').map_or(0, |i| i + 1);
+            let names: Vec<&str> = candidates.iter().map(|s| &s[dir_len..]).collect();
+            self.set_hint(&format!(" {{{}}}", names.join(" | ")));
+        }
+    }
+
+    fn clear_hint(&mut self) {
+        if let Some(x) = self.hint_x.take() {
+            self.row.truncate(x);
+            self.highlight();
+        }
+    }
+
     fn highlight(&mut self) {
+        let hint_idx = self.hint_x.map(|x| self.row.x_to_idx(x));
+
         self.row.faces.clear();
         self.row
             .faces
             .resize(self.prompt_len, (Fg::Prompt, Bg::Default));
+        self.row.faces.resize(
+            hint_idx.unwrap_or(self.row.string.len()),
+            (Fg::Default, Bg::Default),
+        );
         self.row
             .faces
-            .resize(self.row.string.len(), (Fg::Default, Bg::Default));
+            .resize(self.row.string.len(), (Fg::Comment, Bg::Default));
         self.draw = true;
     }
 
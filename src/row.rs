@@ -1,20 +1,20 @@
 extern crate unicode_width;
 
+use std::cell::{Ref, RefCell};
 use std::cmp;
 use std::io::{self, Write};
 use std::ops::Range;
 use unicode_width::UnicodeWidthChar;
 
 use crate::canvas::Canvas;
+use crate::column_map::ColumnMap;
 use crate::face::{Bg, Fg};
-use crate::util::UintVec;
 
-const TAB_WIDTH: usize = 4;
+pub const TAB_WIDTH: usize = 4;
 const ZWJ_WIDTH: usize = 1;
-const TOMBSTONE: usize = 0;
 
 #[inline]
-fn char_width(x: usize, ch: char) -> usize {
+pub fn char_width(x: usize, ch: char) -> usize {
     match ch {
         '\t' => TAB_WIDTH - x % TAB_WIDTH,
         '\u{200d}' => ZWJ_WIDTH,
@@ -26,47 +26,48 @@ fn str_width(x: usize, string: &str) -> usize {
     string.chars().fold(0, |w, ch| w + char_width(x + w, ch))
 }
 
+// Only rows with tabs or non-ASCII characters need a column map
+fn needs_column_map(string: &str) -> bool {
+    string.bytes().any(|b| b == b'\t' || !b.is_ascii())
+}
+
 pub struct Row {
     pub string: String,
     pub context: Option<String>,
     pub faces: Vec<(Fg, Bg)>,
     pub trailing_bg: Bg,
     pub indent_level: usize,
-    x_to_idx: Option<Box<UintVec>>,
+    column_map: Option<Box<ColumnMap>>,
+    // the width the row was last wrapped at and the starts of its lines
+    wrap_starts: RefCell<Option<(usize, Vec<usize>)>>,
 }
 
 impl Row {
     pub fn new(string: &str) -> Self {
-        let mut row = Self {
+        Self {
             string: String::from(string),
             context: None,
             faces: Vec::new(),
             trailing_bg: Bg::Default,
             indent_level: 0,
-            x_to_idx: None,
-        };
-        row.update_mappings();
-        row
+            column_map: needs_column_map(string).then(|| Box::new(ColumnMap::new(string))),
+            wrap_starts: RefCell::new(None),
+        }
     }
 
     #[inline]
     pub fn x_to_idx(&self, x: usize) -> usize {
-        match self.x_to_idx.as_ref() {
-            Some(v) => v.at(x),
+        match self.column_map.as_ref() {
+            Some(_) => self.find_idx(x).unwrap_or(0),
             None => x,
         }
     }
 
     pub fn idx_to_x(&self, idx: usize) -> usize {
-        match self.x_to_idx.as_ref() {
-            Some(v) => {
-                let mut x = 0;
-                loop {
-                    if v.at(x) == idx {
-                        break x;
-                    }
-                    x += 1;
-                }
+        match self.column_map.as_ref() {
+            Some(map) => {
+                let (start, x) = map.seek_idx(idx);
+                x + str_width(x, &self.string[start..idx])
             }
             None => idx,
         }
@@ -74,12 +75,28 @@ impl Row {
 
     #[inline]
     pub fn last_x(&self) -> usize {
-        match self.x_to_idx.as_ref() {
-            Some(v) => v.len() - 1,
+        match self.column_map.as_ref() {
+            Some(map) => map.width(),
             None => self.string.len(),
         }
     }
 
+    // Returns None if the column is in the middle of a wide character
+    fn find_idx(&self, x: usize) -> Option<usize> {
+        let (start, mut cur_x) = self.column_map.as_ref()?.seek_x(x);
+
+        for (idx, ch) in self.string[start..].char_indices() {
+            let width = char_width(cur_x, ch);
+            if cur_x == x && width > 0 {
+                return Some(start + idx);
+            } else if cur_x + width > x {
+                return None;
+            }
+            cur_x += width;
+        }
+        (cur_x == x).then_some(self.string.len())
+    }
+
     pub fn prev_x(&self, x: usize) -> Option<usize> {
         if x > 0 {
             let mut x = x - 1;
@@ -148,10 +165,51 @@ impl Row {
         Some(x)
     }
 
+    // Returns the columns where screen lines start when the row is wrapped
+    // at the width, which are kept until the row is edited
+    pub fn wrap(&self, width: usize) -> Ref<'_, [usize]> {
+        let mut cache = self.wrap_starts.borrow_mut();
+        if cache.as_ref().is_none_or(|&(w, _)| w != width) {
+            *cache = Some((width, self.wrap_starts(width)));
+        }
+        drop(cache);
+        Ref::map(self.wrap_starts.borrow(), |cache| {
+            cache.as_ref().unwrap().1.as_slice()
+        })
+    }
+
+    // Lines break after whitespace where possible, and whitespace may run
+    // past the width instead of starting a line
+    fn wrap_starts(&self, width: usize) -> Vec<usize> {
+        let mut starts = vec![0];
+        let mut start = 0;
+        let mut space_x = 0;
+        let mut x = 0;
+
+        for ch in self.string.chars() {
+            let w = char_width(x, ch);
+            let is_space = ch == ' ' || ch == '\t';
+
+            if !is_space && x > start && x + w > start + width {
+                start = if space_x > start { space_x } else { x };
+                if x > start && x + w > start + width {
+                    starts.push(start);
+                    start = x;
+                }
+                starts.push(start);
+            }
+            x += w;
+            if is_space {
+                space_x = x;
+            }
+        }
+        starts
+    }
+
     #[inline]
     fn is_char_boundary(&self, x: usize) -> bool {
-        match self.x_to_idx.as_ref() {
-            Some(v) => x == 0 || v.at(x) != TOMBSTONE,
+        match self.column_map.as_ref() {
+            Some(_) => x == 0 || self.find_idx(x).is_some(),
             None => true,
         }
     }
@@ -199,20 +257,10 @@ impl Row {
         &self.string[..len]
     }
 
-    pub fn indent(&mut self, string: &str) -> String {
-        let code_part = self.string.split_off(self.indent_part().len());
-        let indent_part = self.string.split_off(0);
-        self.string.push_str(string);
-        self.string.push_str(&code_part);
-        self.update_mappings();
-        indent_part
-    }
-
     pub fn clear(&mut self) {
         self.string.clear();
-        if self.x_to_idx.is_some() {
-            self.update_mappings();
-        }
+        self.column_map = None;
+        *self.wrap_starts.get_mut() = None;
     }
 
     pub fn read_str(&self, x1: usize, x2: usize) -> &str {
@@ -220,78 +268,65 @@ impl Row {
     }
 
     pub fn push_str(&mut self, string: &str) {
+        let idx = self.string.len();
         self.string.push_str(string);
-        self.update_mappings();
+        self.update_caches(idx, 0, string);
     }
 
     pub fn insert_str(&mut self, x: usize, string: &str) -> usize {
         let idx = self.x_to_idx(x);
         self.string.insert_str(idx, string);
-        self.update_mappings();
+        self.update_caches(idx, 0, string);
         x + str_width(x, string)
     }
 
     pub fn remove_str(&mut self, x1: usize, x2: usize) -> String {
         let idx1 = self.x_to_idx(x1);
         let idx2 = self.x_to_idx(x2);
-        let string = self.string.split_off(idx2);
-        let removed = self.string.split_off(idx1);
-        self.string.push_str(&string);
-        if self.x_to_idx.is_some() {
-            self.update_mappings();
-        }
+        let removed: String = self.string.drain(idx1..idx2).collect();
+        self.update_caches(idx1, removed.len(), "");
         removed
     }
 
     pub fn truncate(&mut self, x: usize) {
         let idx = self.x_to_idx(x);
+        let len = self.string.len();
         self.string.truncate(idx);
-        if self.x_to_idx.is_some() {
-            self.update_mappings();
-        }
+        self.update_caches(idx, len - idx, "");
     }
 
-    pub fn split_off(&mut self, x: usize) -> String {
-        let idx = self.x_to_idx(x);
-        let string = self.string.split_off(idx);
-        if self.x_to_idx.is_some() {
-            self.update_mappings();
+    // Called after `removed` bytes at `idx` are replaced with `inserted`
+    fn update_caches(&mut self, idx: usize, removed: usize, inserted: &str) {
+        *self.wrap_starts.get_mut() = None;
+        if let Some(map) = self.column_map.as_mut() {
+            map.splice(&self.string, idx, removed, inserted.len());
+        } else if needs_column_map(inserted) {
+            self.column_map = Some(Box::new(ColumnMap::new(&self.string)));
         }
-        string
     }
 
-    fn update_mappings(&mut self) {
-        let x_to_idx = self.x_to_idx.get_or_insert(Box::new(UintVec::new()));
-        let mut need_mappings = false;
-
-        x_to_idx.clear();
-
-        for (idx, ch) in self.string.char_indices() {
-            let width = char_width(x_to_idx.len(), ch);
-
-            for i in 0..width {
-                x_to_idx.push(if i == 0 { idx } else { TOMBSTONE });
-            }
-            if ch == '\t' || !ch.is_ascii() {
-                need_mappings = true;
-            }
-        }
-
-        x_to_idx.push(self.string.len());
-
-        if !need_mappings {
-            self.x_to_idx = None;
-        }
+    // Returns the face at the byte index, where `overlay` has faces laid over
+    // those of the row, such as for search matches
+    pub fn face_at(&self, idx: usize, overlay: &[(Range<usize>, (Fg, Bg))]) -> (Fg, Bg) {
+        overlay
+            .iter()
+            .find(|(range, _)| range.contains(&idx))
+            .map_or(self.faces[idx], |&(_, face)| face)
     }
 
-    pub fn draw(&self, canvas: &mut Canvas, x_range: Range<usize>) -> io::Result<()> {
+    pub fn draw(
+        &self,
+        canvas: &mut Canvas,
+        x_range: Range<usize>,
+        overlay: &[(Range<usize>, (Fg, Bg))],
+    ) -> io::Result<()> {
         let start_x = self.next_fit_x(x_range.start);
         let end_x = self.prev_fit_x(x_range.end);
         let start = self.x_to_idx(start_x);
         let end = self.x_to_idx(end_x);
 
         if x_range.start < start_x {
-            canvas.set_bg_color(self.faces[start - 1].1)?;
+            canvas.set_bg_color(self.face_at(start - 1, overlay).1)?;
             canvas.write_repeat(b" ", start_x - x_range.start)?;
         }
 
@@ -300,7 +335,7 @@ impl Row {
         for (idx, ch) in self.string[start..end].char_indices() {
             let idx = start + idx;
             let width = char_width(x, ch);
-            let (fg, bg) = self.faces[idx];
+            let (fg, bg) = self.face_at(idx, overlay);
 
             canvas.set_fg_color(fg)?;
             canvas.set_bg_color(bg)?;
@@ -324,7 +359,7 @@ impl Row {
         }
 
         if end_x < x_range.end && x_range.end <= self.last_x() {
-            canvas.set_bg_color(self.faces[end].1)?;
+            canvas.set_bg_color(self.face_at(end, overlay).1)?;
             canvas.write_repeat(b" ", x_range.end - end_x)?;
         }
 
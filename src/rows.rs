@@ -1,35 +1,168 @@
+use ropey::{Rope, RopeBuilder};
+use std::borrow::Cow;
+use std::cell::{Cell, OnceCell};
+use std::cmp;
 use std::io::{self, Write};
-use std::ops::Range;
+use std::iter::Enumerate;
+use std::ops::{Index, IndexMut, Range};
 
 use crate::canvas::Canvas;
-use crate::coord::Pos;
+use crate::coord::{Pos, Size};
+use crate::face::{Bg, Fg};
+use crate::line_ending::LineEnding;
+use crate::line_tree::{self, LineTree};
 use crate::row::Row;
 
-pub type Rows = Vec<Row>;
-
-pub trait RowsMethods {
-    fn last_pos(&self) -> Pos;
-    fn prev_pos(&self, pos: Pos) -> Option<Pos>;
-    fn next_pos(&self, pos: Pos) -> Option<Pos>;
-    fn prev_word_pos(&self, pos: Pos) -> Option<Pos>;
-    fn next_word_pos(&self, pos: Pos) -> Option<Pos>;
-    fn read_str(&self, pos1: Pos, pos2: Pos) -> String;
-    fn insert_str(&mut self, pos: Pos, string: &str) -> Pos;
-    fn remove_str(&mut self, pos1: Pos, pos2: Pos) -> String;
-    fn draw(
-        &self,
-        canvas: &mut Canvas,
-        x_range: Range<usize>,
-        y_range: Range<usize>,
-    ) -> io::Result<()>;
+// Rows kept built at most before those out of use are evicted, unless a
+// larger share of the rows is allowed so that the scan for eviction takes
+// amortized constant time per row built
+const MAX_BUILT_ROWS: usize = 10_000;
+const MAX_BUILT_SHARE: usize = 16;
+
+// The text is kept in a rope, and each row is built from it on first access.
+// Edits within a line update its row in place.
+pub struct Rows {
+    text: Rope,
+    slots: LineTree<Slot>,
+    // the number of built rows, counted up to the next eviction
+    built: Cell<usize>,
+}
+
+// An evicted row leaves its syntax context behind, so that it can be
+// highlighted again without going through the rows above it
+#[derive(Default)]
+struct Slot {
+    row: OnceCell<Box<Row>>,
+    // the context of the row since it was evicted until it is restored
+    context: Option<Box<str>>,
 }
 
-impl RowsMethods for Rows {
-    fn last_pos(&self) -> Pos {
+impl Slot {
+    fn get_or_build(&self, text: &Rope, y: usize, built: &Cell<usize>) -> &Row {
+        self.row.get_or_init(|| {
+            built.set(built.get() + 1);
+            let mut row = Row::new(&line_of(text, y));
+            row.context = self.context.as_deref().map(String::from);
+            Box::new(row)
+        })
+    }
+}
+
+fn line_of(text: &Rope, y: usize) -> Cow<'_, str> {
+    let line = text.line(y);
+    let len = line.len_bytes();
+    if len > 0 && line.byte(len - 1) == b'\n' {
+        line.byte_slice(..(len - 1)).into()
+    } else {
+        line.into()
+    }
+}
+
+impl Rows {
+    pub fn new(text: &str) -> Self {
+        Self::from_rope(Rope::from_str(text))
+    }
+
+    fn from_rope(text: Rope) -> Self {
+        let slots = LineTree::new(text.len_lines());
+        Self {
+            text,
+            slots,
+            built: Cell::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn byte_len(&self) -> usize {
+        self.text.len_bytes()
+    }
+
+    pub fn line(&self, y: usize) -> Cow<'_, str> {
+        line_of(&self.text, y)
+    }
+
+    // Returns the whole text with the given separator between lines
+    pub fn text(&self, separator: &str) -> String {
+        let mut text = String::with_capacity(self.text.len_bytes());
+        for chunk in self.text.chunks() {
+            if separator == "\n" {
+                text.push_str(chunk);
+            } else {
+                text.push_str(&chunk.replace('\n', separator));
+            }
+        }
+        text
+    }
+
+    // Converts a byte index of the whole text into a line and a byte index in it
+    pub fn locate(&self, idx: usize) -> (usize, usize) {
+        let y = self.text.byte_to_line(idx);
+        (y, idx - self.text.line_to_byte(y))
+    }
+
+    // Iterates over the rows in the range, building them as needed
+    pub fn iter_mut(&mut self, range: Range<usize>) -> IterMut<'_> {
+        IterMut {
+            text: &self.text,
+            built: &self.built,
+            start: range.start,
+            slots: self.slots.iter_mut(range).enumerate(),
+        }
+    }
+
+    pub fn clear_contexts(&mut self) {
+        self.slots.retain_chunks(|_, slots| {
+            for slot in slots.iter_mut() {
+                slot.context = None;
+                if let Some(row) = slot.row.get_mut() {
+                    row.context = None;
+                }
+            }
+            slots.iter().any(|slot| slot.row.get().is_some())
+        });
+    }
+
+    // Drops the built rows out of the ranges once there are too many
+    pub fn evict(&mut self, keep: &[Range<usize>]) {
+        if self.built.get() <= cmp::max(MAX_BUILT_ROWS, self.len() / MAX_BUILT_SHARE) {
+            return;
+        }
+        let mut built = 0;
+        self.slots.retain_chunks(|start, slots| {
+            for (i, slot) in slots.iter_mut().enumerate() {
+                if keep.iter().any(|range| range.contains(&(start + i))) {
+                    built += slot.row.get().is_some() as usize;
+                } else if let Some(row) = slot.row.take() {
+                    if let Some(context) = row.context {
+                        slot.context = Some(context.into());
+                    }
+                }
+            }
+            // chunks left with nothing are freed
+            slots
+                .iter()
+                .any(|slot| slot.row.get().is_some() || slot.context.is_some())
+        });
+        self.built.set(built);
+    }
+
+    // Puts the context of an evicted row back, returning the row to be
+    // highlighted again
+    pub fn restore(&mut self, y: usize) -> Option<IterMut<'_>> {
+        let context = self.slots.get_mut(y).context.take()?;
+        // a row built since then may have a newer one
+        self[y].context.get_or_insert_with(|| context.into());
+        Some(self.iter_mut(y..(y + 1)))
+    }
+
+    pub fn last_pos(&self) -> Pos {
         Pos::new(self[self.len() - 1].last_x(), self.len() - 1)
     }
 
-    fn prev_pos(&self, pos: Pos) -> Option<Pos> {
+    pub fn prev_pos(&self, pos: Pos) -> Option<Pos> {
         if let Some(x) = self[pos.y].prev_x(pos.x) {
             Some(Pos::new(x, pos.y))
         } else if pos.y > 0 {
@@ -39,7 +172,7 @@ impl RowsMethods for Rows {
         }
     }
 
-    fn next_pos(&self, pos: Pos) -> Option<Pos> {
+    pub fn next_pos(&self, pos: Pos) -> Option<Pos> {
         if let Some(x) = self[pos.y].next_x(pos.x) {
             Some(Pos::new(x, pos.y))
         } else if pos.y < self.len() - 1 {
@@ -49,7 +182,7 @@ impl RowsMethods for Rows {
         }
     }
 
-    fn prev_word_pos(&self, pos: Pos) -> Option<Pos> {
+    pub fn prev_word_pos(&self, pos: Pos) -> Option<Pos> {
         if let Some(x) = self[pos.y].prev_word_x(pos.x) {
             return Some(Pos::new(x, pos.y));
         }
@@ -61,8 +194,7 @@ impl RowsMethods for Rows {
         None
     }
 
-    #[allow(clippy::needless_range_loop)]
-    fn next_word_pos(&self, pos: Pos) -> Option<Pos> {
+    pub fn next_word_pos(&self, pos: Pos) -> Option<Pos> {
         if let Some(x) = self[pos.y].next_word_x(pos.x) {
             return Some(Pos::new(x, pos.y));
         }
@@ -74,70 +206,287 @@ impl RowsMethods for Rows {
         None
     }
 
-    #[allow(clippy::needless_range_loop)]
-    fn read_str(&self, pos1: Pos, pos2: Pos) -> String {
-        let mut strings = Vec::new();
+    pub fn read_str(&self, pos1: Pos, pos2: Pos) -> String {
+        let idx1 = self.byte_idx(pos1);
+        let idx2 = self.byte_idx(pos2);
+        self.text.byte_slice(idx1..idx2).into()
+    }
 
-        for y in pos1.y..=pos2.y {
-            let row = &self[y];
-            let x1 = if y == pos1.y { pos1.x } else { 0 };
-            let x2 = if y == pos2.y { pos2.x } else { row.last_x() };
-            strings.push(row.read_str(x1, x2));
+    pub fn insert_str(&mut self, pos: Pos, string: &str) -> Pos {
+        let idx = self.byte_idx(pos);
+        self.text.insert(self.text.byte_to_char(idx), string);
+
+        if !string.contains('\n') {
+            let x = self[pos.y].insert_str(pos.x, string);
+            return Pos::new(x, pos.y);
         }
-        strings.join("\n")
-    }
 
-    fn insert_str(&mut self, pos: Pos, string: &str) -> Pos {
         let strings: Vec<&str> = string.split('\n').collect();
+        let y = pos.y + strings.len() - 1;
+        self.slots.splice(pos.y + 1, 0, y - pos.y);
+        self.rebuild(pos.y);
 
-        if strings.len() == 1 {
-            let x = self[pos.y].insert_str(pos.x, strings[0]);
-            Pos::new(x, pos.y)
-        } else {
-            let string = self[pos.y].split_off(pos.x);
-            let mut rows = self.split_off(pos.y + 1);
-            self[pos.y].push_str(strings[0]);
-            self.append(&mut strings[1..].iter().map(|&s| Row::new(s)).collect());
-            let pos = self.last_pos();
-            self[pos.y].push_str(&string);
-            self.append(&mut rows);
-            pos
-        }
+        let end = strings[strings.len() - 1].len();
+        Pos::new(self[y].idx_to_x(end), y)
     }
 
-    fn remove_str(&mut self, pos1: Pos, pos2: Pos) -> String {
+    pub fn remove_str(&mut self, pos1: Pos, pos2: Pos) -> String {
+        let idx1 = self.byte_idx(pos1);
+        let idx2 = self.byte_idx(pos2);
+        let removed = self.text.byte_slice(idx1..idx2).into();
+        self.text
+            .remove(self.text.byte_to_char(idx1)..self.text.byte_to_char(idx2));
+
         if pos1.y == pos2.y {
-            self[pos1.y].remove_str(pos1.x, pos2.x)
-        } else {
-            let mut removed = vec![self[pos1.y].split_off(pos1.x)];
-            let string = self[pos2.y].split_off(pos2.x);
-            let mut rows = self.split_off(pos2.y + 1);
-            self[pos1.y].push_str(&string);
-            removed.append(
-                &mut self
-                    .split_off(pos1.y + 1)
-                    .into_iter()
-                    .map(|row| row.string)
-                    .collect(),
-            );
-            self.append(&mut rows);
-            removed.join("\n")
-        }
-    }
-
-    fn draw(
+            self[pos1.y].remove_str(pos1.x, pos2.x);
+            return removed;
+        }
+
+        self.slots.splice(pos1.y + 1, pos2.y - pos1.y, 0);
+        self.rebuild(pos1.y);
+        removed
+    }
+
+    // Replaces the indentation of a line, returning the previous one
+    pub fn indent(&mut self, y: usize, string: &str) -> String {
+        let start = self.text.line_to_byte(y);
+        let indent_part = self[y].indent_part().to_string();
+        let end = start + indent_part.len();
+        self.text
+            .remove(self.text.byte_to_char(start)..self.text.byte_to_char(end));
+        self.text.insert(self.text.byte_to_char(start), string);
+
+        let row = &mut self[y];
+        let x = row.idx_to_x(indent_part.len());
+        row.remove_str(0, x);
+        row.insert_str(0, string);
+        indent_part
+    }
+
+    fn byte_idx(&self, pos: Pos) -> usize {
+        self.text.line_to_byte(pos.y) + self[pos.y].x_to_idx(pos.x)
+    }
+
+    // Builds a row again from the text edited across lines, keeping its
+    // syntax context
+    fn rebuild(&mut self, y: usize) {
+        let slot = self.slots.get_mut(y);
+        let context = match slot.row.take() {
+            Some(row) => row.context,
+            None => slot.context.as_deref().map(String::from),
+        };
+        slot.context = None;
+        let mut row = Row::new(&line_of(&self.text, y));
+        row.context = context;
+        self.slots.get_mut(y).row = OnceCell::from(Box::new(row));
+        self.built.set(self.built.get() + 1);
+    }
+
+    // `overlay` gives the faces laid over each row; see `Row::face_at`
+    pub fn draw(
         &self,
         canvas: &mut Canvas,
+        pos: Pos,
         x_range: Range<usize>,
         y_range: Range<usize>,
+        overlay: impl Fn(usize) -> Vec<(Range<usize>, (Fg, Bg))>,
     ) -> io::Result<()> {
-        for y in y_range {
+        let width = x_range.len();
+
+        for (i, y) in y_range.enumerate() {
+            canvas.set_cursor(pos.x, pos.y + i)?;
             if y < self.len() {
-                self[y].draw(canvas, x_range.clone())?;
+                let row = &self[y];
+                row.draw(canvas, x_range.clone(), &overlay(y))?;
+                let drawn = row.last_x().min(x_range.end).saturating_sub(x_range.start);
+                canvas.write_repeat(b" ", width - drawn)?;
+            } else {
+                canvas.set_bg_color(Bg::Default)?;
+                canvas.write_repeat(b" ", width)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Draws the rows wrapped at the width, short of that of the window so
+    // that a backslash fits where a line continues. Only the screen lines
+    // from row `start` are drawn, as the ones above it are unchanged.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_wrapped(
+        &self,
+        canvas: &mut Canvas,
+        pos: Pos,
+        offset: Pos,
+        size: Size,
+        width: usize,
+        start: usize,
+        overlay: impl Fn(usize) -> Vec<(Range<usize>, (Fg, Bg))>,
+    ) -> io::Result<()> {
+        let mut i = 0;
+
+        for y in offset.y.. {
+            if i == size.h {
+                break;
+            }
+            if y >= self.len() {
+                if y >= start {
+                    canvas.set_cursor(pos.x, pos.y + i)?;
+                    canvas.set_bg_color(Bg::Default)?;
+                    canvas.write_repeat(b" ", size.w)?;
+                }
+                i += 1;
+                continue;
+            }
+
+            let row = &self[y];
+            let faces = if y >= start { overlay(y) } else { Vec::new() };
+            let starts = row.wrap(width);
+            for (j, &x1) in starts.iter().enumerate() {
+                if i == size.h {
+                    break;
+                }
+                if y == offset.y && x1 < offset.x {
+                    continue;
+                }
+                if y >= start {
+                    let x2 = starts.get(j + 1).copied();
+                    let end = x2.unwrap_or(row.last_x()).min(x1 + width);
+                    canvas.set_cursor(pos.x, pos.y + i)?;
+                    row.draw(canvas, x1..end, &faces)?;
+                    if let Some(x2) = x2 {
+                        canvas.set_bg_color(row.face_at(row.x_to_idx(x2), &faces).1)?;
+                        canvas.write_repeat(b" ", width - (end - x1))?;
+                        canvas.set_fg_color(Fg::Default)?;
+                        canvas.set_bg_color(Bg::Default)?;
+                        canvas.write(b"\\")?;
+                    } else {
+                        canvas.write_repeat(b" ", size.w.saturating_sub(end - x1))?;
+                    }
+                }
+                i += 1;
             }
-            canvas.write(b"\x1b[K")?;
-            canvas.write(b"\r\n")?;
         }
         Ok(())
     }
 }
+
+impl Index<usize> for Rows {
+    type Output = Row;
+
+    fn index(&self, y: usize) -> &Row {
+        self.slots.get(y).get_or_build(&self.text, y, &self.built)
+    }
+}
+
+impl IndexMut<usize> for Rows {
+    fn index_mut(&mut self, y: usize) -> &mut Row {
+        let slot = self.slots.get_mut(y);
+        slot.get_or_build(&self.text, y, &self.built);
+        slot.row.get_mut().unwrap()
+    }
+}
+
+// Builds rows from text given a chunk at a time. CRLF line endings are
+// turned into LF if the first line ends with CRLF.
+#[derive(Default)]
+pub struct RowsBuilder {
+    builder: RopeBuilder,
+    crlf: Option<bool>,
+    // whether a CR at the end of the last chunk is held back
+    cr: bool,
+}
+
+impl RowsBuilder {
+    pub fn append(&mut self, mut chunk: &str) {
+        if chunk.is_empty() {
+            return;
+        }
+        if self.crlf.is_none() {
+            if let Some(i) = chunk.find('\n') {
+                let cr = if i > 0 {
+                    chunk.as_bytes()[i - 1] == b'\r'
+                } else {
+                    self.cr
+                };
+                self.crlf = Some(cr);
+            }
+        }
+        let crlf = self.crlf == Some(true);
+        if self.cr && !(crlf && chunk.starts_with('\n')) {
+            self.builder.append("\r");
+        }
+        self.cr = chunk.ends_with('\r');
+        if self.cr {
+            chunk = &chunk[..chunk.len() - 1];
+        }
+        if crlf {
+            self.builder.append(&chunk.replace("\r\n", "\n"));
+        } else {
+            self.builder.append(chunk);
+        }
+    }
+
+    pub fn finish(mut self) -> (Rows, LineEnding) {
+        if self.cr {
+            self.builder.append("\r");
+        }
+        let line_ending = match self.crlf {
+            Some(true) => LineEnding::Crlf,
+            _ => LineEnding::Lf,
+        };
+        (Rows::from_rope(self.builder.finish()), line_ending)
+    }
+}
+
+pub struct IterMut<'a> {
+    text: &'a Rope,
+    built: &'a Cell<usize>,
+    slots: Enumerate<line_tree::IterMut<'a, Slot>>,
+    start: usize,
+}
+
+impl<'a> Iterator for IterMut<'a> {
+    type Item = &'a mut Row;
+
+    fn next(&mut self) -> Option<&'a mut Row> {
+        let (i, slot) = self.slots.next()?;
+        slot.get_or_build(self.text, self.start + i, self.built);
+        slot.row.get_mut().map(|row| &mut **row)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.slots.size_hint()
+    }
+}
+
+impl ExactSizeIterator for IterMut<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A CR held back at the end of a chunk must pair up with an LF at the
+    // start of the next one
+    #[test]
+    fn build_in_chunks() {
+        for text in ["a\r\nb\r\n\r\nc\rd\r", "a\nb\r\nc\r", "\r\r\n\n\r"] {
+            let crlf = text.find('\n').is_some_and(|i| text[..i].ends_with('\r'));
+            let expected = if crlf {
+                text.replace("\r\n", "\n")
+            } else {
+                text.to_string()
+            };
+            for i in 0..=text.len() {
+                for j in i..=text.len() {
+                    let mut builder = RowsBuilder::default();
+                    builder.append(&text[..i]);
+                    builder.append(&text[i..j]);
+                    builder.append(&text[j..]);
+                    let (rows, line_ending) = builder.finish();
+                    assert_eq!(rows.text("\n"), expected);
+                    assert_eq!(line_ending == LineEnding::Crlf, crlf);
+                }
+            }
+        }
+    }
+}
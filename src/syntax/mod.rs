@@ -5,7 +5,7 @@ mod rust;
 use std::path::Path;
 
 use crate::canvas::Term;
-use crate::row::Row;
+use crate::rows::IterMut;
 use crate::syntax::plain::Plain;
 use crate::syntax::ruby::Ruby;
 use crate::syntax::rust::Rust;
@@ -18,7 +18,7 @@ pub trait Syntax {
     fn fg_color(&self, term: Term) -> &'static [u8];
     fn bg_color(&self, term: Term) -> &'static [u8];
     fn indent_unit(&self) -> Option<&'static str>;
-    fn update_rows(&self, rows: &mut [Row]) -> usize;
+    fn update_rows(&self, rows: IterMut) -> usize;
 }
 
 impl dyn Syntax {
@@ -1,6 +1,6 @@
 use crate::canvas::Term;
 use crate::face::{Bg, Fg};
-use crate::row::Row;
+use crate::rows::IterMut;
 use crate::syntax::Syntax;
 
 pub struct Plain;
@@ -34,8 +34,9 @@ impl Syntax for Plain {
         None
     }
 
-    fn update_rows(&self, rows: &mut [Row]) -> usize {
-        for (i, row) in rows.iter_mut().enumerate() {
+    fn update_rows(&self, rows: IterMut) -> usize {
+        let len = rows.len();
+        for (i, row) in rows.enumerate() {
             if i > 0 && row.context.is_some() {
                 return i;
             }
@@ -46,6 +47,6 @@ impl Syntax for Plain {
             row.trailing_bg = Bg::Default;
         }
 
-        rows.len()
+        len
     }
 }
@@ -5,6 +5,7 @@ use self::TokenKind::*;
 use crate::canvas::Term;
 use crate::face::{Bg, Fg};
 use crate::row::Row;
+use crate::rows::IterMut;
 use crate::syntax::Syntax;
 
 pub struct Rust;
@@ -38,11 +39,12 @@ impl Syntax for Rust {
         Some("    ")
     }
 
-    fn update_rows(&self, rows: &mut [Row]) -> usize {
+    fn update_rows(&self, rows: IterMut) -> usize {
+        let len = rows.len();
         let mut context_v = Vec::new();
         let mut context_s = String::new();
 
-        for (i, row) in rows.iter_mut().enumerate() {
+        for (i, row) in rows.enumerate() {
             if i == 0 {
                 if row.context.is_none() {
                     row.context = Some(String::new());
@@ -61,7 +63,7 @@ impl Syntax for Rust {
             self.update_row(row, &mut context_v, &mut context_s);
         }
 
-        rows.len()
+        len
     }
 }
 
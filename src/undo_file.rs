@@ -0,0 +1,132 @@
+use std::fs::{self, DirBuilder};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+
+use crate::coord::Pos;
+use crate::edit::{Edit, EditKind};
+use crate::undo_tree::UndoTree;
+use crate::util::{cache_dir, escape, fnv1a, unescape, write_atomic_with_mode};
+
+const HEADER: &str = "ele-undo 2";
+
+// The undo tree of a file, valid only while its contents are unchanged
+pub struct UndoFile {
+    pub undo_tree: UndoTree,
+}
+
+impl UndoFile {
+    pub fn load(file_path: &str, bytes: &[u8]) -> Option<Self> {
+        let string = fs::read_to_string(Self::path(file_path)?).ok()?;
+        let mut lines = string.lines();
+
+        if lines.next() != Some(HEADER) {
+            return None;
+        }
+        if lines.next()? != format!("hash {:016x}", fnv1a(bytes)) {
+            return None;
+        }
+        let current = lines.next()?.strip_prefix("current ")?.parse().ok()?;
+
+        let mut nodes: Vec<(usize, usize, Vec<Edit>)> = Vec::new();
+        for line in lines {
+            match line.split_once(' ')? {
+                ("node", args) => {
+                    let (parent, active) = args.split_once(' ')?;
+                    nodes.push((parent.parse().ok()?, active.parse().ok()?, Vec::new()));
+                }
+                ("e", edit) => nodes.last_mut()?.2.push(parse_edit(edit)?),
+                _ => return None,
+            }
+        }
+        let undo_tree = UndoTree::from_nodes(nodes, current)?;
+        Some(Self { undo_tree })
+    }
+
+    pub fn save(file_path: &str, bytes: &[u8], undo_tree: &UndoTree) -> io::Result<()> {
+        let path = match Self::path(file_path) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        // the history holds the text, so keep it as private as it can be
+        if let Some(dir) = path.parent() {
+            DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+
+        write_atomic_with_mode(&path, 0o600, |writer| {
+            writeln!(writer, "{}", HEADER)?;
+            writeln!(writer, "hash {:016x}", fnv1a(bytes))?;
+            writeln!(writer, "current {}", undo_tree.current())?;
+            for node in undo_tree.nodes() {
+                writeln!(writer, "node {} {}", node.parent, node.active)?;
+                for edit in node.edits.iter() {
+                    writeln!(writer, "e {}", format_edit(edit))?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // Files are keyed by their real path, so symlinks share one history
+    fn path(file_path: &str) -> Option<PathBuf> {
+        let path = fs::canonicalize(file_path).ok()?;
+        let key = fnv1a(path.as_os_str().as_bytes());
+        cache_dir().map(|dir| dir.join("undo").join(format!("{:016x}", key)))
+    }
+}
+
+fn format_edit(edit: &Edit) -> String {
+    match &edit.kind {
+        EditKind::Insert(pos, string, mv) => format!(
+            "{} i {} {} {} {}",
+            edit.time,
+            pos.x,
+            pos.y,
+            *mv as u8,
+            escape(string)
+        ),
+        EditKind::Remove(pos1, pos2, mv) => format!(
+            "{} r {} {} {} {} {}",
+            edit.time, pos1.x, pos1.y, pos2.x, pos2.y, *mv as u8
+        ),
+        EditKind::Indent(pos, string) => {
+            format!("{} n {} {} {}", edit.time, pos.x, pos.y, escape(string))
+        }
+    }
+}
+
+fn parse_edit(line: &str) -> Option<Edit> {
+    let mut fields = line.splitn(2, ' ');
+    let time = fields.next()?.parse().ok()?;
+    let (kind, args) = fields.next()?.split_once(' ')?;
+
+    let edit = match kind {
+        "i" => {
+            let mut args = args.splitn(4, ' ');
+            let pos = parse_pos(args.next()?, args.next()?)?;
+            let mv = args.next()? == "1";
+            Edit::insert(time, pos, unescape(args.next()?), mv)
+        }
+        "r" => {
+            let args: Vec<&str> = args.split(' ').collect();
+            if args.len() != 5 {
+                return None;
+            }
+            let pos1 = parse_pos(args[0], args[1])?;
+            let pos2 = parse_pos(args[2], args[3])?;
+            Edit::remove(time, pos1, pos2, args[4] == "1")
+        }
+        "n" => {
+            let mut args = args.splitn(3, ' ');
+            let pos = parse_pos(args.next()?, args.next()?)?;
+            Edit::indent(time, pos, unescape(args.next()?))
+        }
+        _ => return None,
+    };
+    Some(edit)
+}
+
+fn parse_pos(x: &str, y: &str) -> Option<Pos> {
+    Some(Pos::new(x.parse().ok()?, y.parse().ok()?))
+}
@@ -0,0 +1,217 @@
+use std::mem;
+
+use crate::edit::{Edit, EditKind};
+
+// Every state the buffer has been in, with node 0 as the original state
+pub struct UndoTree {
+    nodes: Vec<Node>,
+    current: usize,
+}
+
+pub struct Node {
+    pub parent: usize,
+    pub children: Vec<usize>,
+    // the child that redo follows
+    pub active: usize,
+    // undo edits while the node is applied, redo edits otherwise,
+    // processed from the last one in both cases
+    pub edits: Vec<Edit>,
+}
+
+impl UndoTree {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![Node::new(0)],
+            current: 0,
+        }
+    }
+
+    // Rebuilds a tree whose nodes are ordered so that parents come first
+    pub fn from_nodes(nodes: Vec<(usize, usize, Vec<Edit>)>, current: usize) -> Option<Self> {
+        let mut tree = Self {
+            nodes: Vec::new(),
+            current,
+        };
+        for (i, (parent, active, edits)) in nodes.into_iter().enumerate() {
+            if i > 0 {
+                tree.nodes
+                    .get_mut(parent)
+                    .filter(|_| parent < i)?
+                    .children
+                    .push(i);
+            }
+            let mut node = Node::new(parent);
+            node.active = active;
+            node.edits = edits;
+            tree.nodes.push(node);
+        }
+
+        let valid = |node: &Node| node.children.is_empty() || node.active < node.children.len();
+        if current < tree.nodes.len() && tree.nodes.iter().all(valid) {
+            Some(tree)
+        } else {
+            None
+        }
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    // Identifies the current state, or None for the original state
+    pub fn time(&self) -> Option<usize> {
+        self.nodes[self.current].edits.first().map(|e| e.time)
+    }
+
+    pub fn next_time(&self) -> usize {
+        self.nodes
+            .iter()
+            .flat_map(|node| &node.edits)
+            .map(|e| e.time + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Edits with the same time as the current state belong to it,
+    // others start a new branch from it
+    pub fn push(&mut self, edit: Edit) {
+        let node = &self.nodes[self.current];
+        let same_step = node
+            .edits
+            .last()
+            .map_or(self.current > 0, |e| e.time == edit.time);
+
+        if !same_step {
+            let index = self.nodes.len();
+            let parent = &mut self.nodes[self.current];
+            parent.active = parent.children.len();
+            parent.children.push(index);
+            self.nodes.push(Node::new(self.current));
+            self.current = index;
+        }
+        self.nodes[self.current].edits.push(edit);
+    }
+
+    pub fn pop(&mut self) -> Option<Edit> {
+        if self.current == 0 {
+            return None;
+        }
+        self.nodes[self.current].edits.pop()
+    }
+
+    // Moves to the parent state, returning the edits to process
+    pub fn undo(&mut self) -> Option<(usize, Vec<Edit>)> {
+        if self.current == 0 {
+            return None;
+        }
+        let index = self.current;
+        self.select(index);
+        self.current = self.nodes[index].parent;
+        Some((index, mem::take(&mut self.nodes[index].edits)))
+    }
+
+    // Moves to the active child state, returning the edits to process
+    pub fn redo(&mut self) -> Option<(usize, Vec<Edit>)> {
+        let node = &self.nodes[self.current];
+        let index = *node.children.get(node.active)?;
+        self.current = index;
+        Some((index, mem::take(&mut self.nodes[index].edits)))
+    }
+
+    // Stores back the edits that reverse the processed ones
+    pub fn restore(&mut self, index: usize, edits: Vec<Edit>) {
+        self.nodes[index].edits = edits;
+    }
+
+    // Makes redo follow the next branch, returning its number and the count
+    pub fn switch_branch(&mut self) -> Option<(usize, usize)> {
+        let node = &mut self.nodes[self.current];
+        let len = node.children.len();
+        if len < 2 {
+            return None;
+        }
+        node.active = (node.active + 1) % len;
+        Some((node.active + 1, len))
+    }
+
+    // Returns the number of undo steps and the nodes to redo into
+    pub fn path_to(&self, index: usize) -> (usize, Vec<usize>) {
+        let ancestors = self.ancestors(self.current);
+        let mut redos = Vec::new();
+        let mut i = index;
+        while !ancestors.contains(&i) {
+            redos.push(i);
+            i = self.nodes[i].parent;
+        }
+        redos.reverse();
+        let undos = ancestors.iter().position(|&a| a == i).unwrap();
+        (undos, redos)
+    }
+
+    pub fn select(&mut self, index: usize) {
+        let parent = self.nodes[index].parent;
+        let node = &mut self.nodes[parent];
+        node.active = node.children.iter().position(|&i| i == index).unwrap();
+    }
+
+    // Draws the tree as lines of text, each paired with its node
+    pub fn render(&self) -> Vec<(String, usize)> {
+        let mut lines = Vec::new();
+        let mut stack = vec![(0, String::new(), String::new())];
+
+        while let Some((index, prefix, child_prefix)) = stack.pop() {
+            let node = &self.nodes[index];
+            let mark = if index == self.current { "*" } else { "o" };
+            let line = format!("{}{} {}  {}", prefix, mark, index, node.describe());
+            lines.push((line, index));
+
+            let len = node.children.len();
+            for (i, &child) in node.children.iter().enumerate().rev() {
+                let (head, rest) = match (len, i + 1 == len) {
+                    (1, _) => ("", ""),
+                    (_, false) => ("+- ", "|  "),
+                    (_, true) => ("`- ", "   "),
+                };
+                let prefix = format!("{}{}", child_prefix, head);
+                stack.push((child, prefix, format!("{}{}", child_prefix, rest)));
+            }
+        }
+        lines
+    }
+
+    fn ancestors(&self, mut index: usize) -> Vec<usize> {
+        let mut ancestors = vec![index];
+        while index > 0 {
+            index = self.nodes[index].parent;
+            ancestors.push(index);
+        }
+        ancestors
+    }
+}
+
+impl Node {
+    fn new(parent: usize) -> Self {
+        Self {
+            parent,
+            children: Vec::new(),
+            active: 0,
+            edits: Vec::new(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        let y = match self.edits.first().map(|e| &e.kind) {
+            Some(
+                EditKind::Insert(pos, _, _)
+                | EditKind::Remove(pos, _, _)
+                | EditKind::Indent(pos, _),
+            ) => pos.y,
+            None => return "original".into(),
+        };
+        format!("line {}", y + 1)
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct DrawRange {
     start: Option<usize>,
     end: Option<usize>,
@@ -0,0 +1,100 @@
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, BufWriter, ErrorKind};
+use std::os::unix::fs::{chown, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+
+pub fn write_atomic<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    write_with_mode(path, None, write)
+}
+
+// Writes a file with the given permissions instead of those of the file
+// it replaces, for files that may hold private text
+pub fn write_atomic_with_mode<F>(path: &Path, mode: u32, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    write_with_mode(path, Some(mode), write)
+}
+
+fn write_with_mode<F>(path: &Path, mode: Option<u32>, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    // write through symlinks to the real file
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let metadata = fs::metadata(&target).ok();
+
+    let temp = temp_path(&target);
+    let file = match open_new(&temp, mode) {
+        Ok(file) => file,
+        // the directory is not writable but the file itself may be
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            return write_in_place(&target, mode, write);
+        }
+        Err(e) => return Err(e),
+    };
+
+    if let Some(metadata) = metadata.as_ref() {
+        let permissions = mode.map_or(metadata.permissions(), Permissions::from_mode);
+        let preserved = fs::set_permissions(&temp, permissions)
+            .and_then(|_| chown(&temp, Some(metadata.uid()), Some(metadata.gid())));
+        if preserved.is_err() {
+            // renaming would change the owner, so overwrite the file instead
+            fs::remove_file(&temp)?;
+            return write_in_place(&target, mode, write);
+        }
+    }
+
+    let result = write_and_sync(file, write).and_then(|_| fs::rename(&temp, &target));
+    if result.is_err() {
+        fs::remove_file(&temp).unwrap_or(());
+        return result;
+    }
+
+    if let Some(dir) = target.parent() {
+        File::open(dir).and_then(|d| d.sync_all()).unwrap_or(());
+    }
+    Ok(())
+}
+
+fn write_in_place<F>(path: &Path, mode: Option<u32>, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let file = File::create(path)?;
+    if let Some(mode) = mode {
+        file.set_permissions(Permissions::from_mode(mode))?;
+    }
+    write_and_sync(file, write)
+}
+
+fn open_new(path: &Path, mode: Option<u32>) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    if let Some(mode) = mode {
+        options.mode(mode);
+    }
+    options.open(path)
+}
+
+fn write_and_sync<F>(file: File, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let mut writer = BufWriter::new(file);
+    write(&mut writer)?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()
+}
+
+fn temp_path(target: &Path) -> PathBuf {
+    let name = target
+        .file_name()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or_default();
+    target.with_file_name(format!(".{}.{}.tmp", name, process::id()))
+}
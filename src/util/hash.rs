@@ -0,0 +1,6 @@
+// FNV-1a, which is stable across runs and Rust versions unlike DefaultHasher
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
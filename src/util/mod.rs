@@ -1,5 +1,11 @@
 mod draw_range;
-mod uint_vec;
+mod escape;
+mod file;
+mod hash;
+mod path;
 
 pub use crate::util::draw_range::DrawRange;
-pub use crate::util::uint_vec::UintVec;
+pub use crate::util::escape::{escape, unescape};
+pub use crate::util::file::{write_atomic, write_atomic_with_mode};
+pub use crate::util::hash::fnv1a;
+pub use crate::util::path::{abbreviate_path, cache_dir, config_dir, data_dir, expand_path};
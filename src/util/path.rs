@@ -0,0 +1,46 @@
+use std::env;
+use std::path::PathBuf;
+
+pub fn expand_path(path: &str) -> String {
+    match (path.strip_prefix('~'), env::var("HOME")) {
+        (Some(rest), Ok(home)) if rest.is_empty() || rest.starts_with('/') => home + rest,
+        _ => String::from(path),
+    }
+}
+
+pub fn abbreviate_path(path: &str) -> String {
+    match env::var("HOME") {
+        Ok(home) if !home.is_empty() && path.starts_with(&home) => {
+            let rest = &path[home.len()..];
+            if rest.is_empty() || rest.starts_with('/') {
+                format!("~{}", rest)
+            } else {
+                String::from(path)
+            }
+        }
+        _ => String::from(path),
+    }
+}
+
+// The directory for user settings
+pub fn config_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+// The directory for persistent state, such as the minibuffer history
+pub fn data_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_DATA_HOME", ".local/share")
+}
+
+// The directory for disposable data, such as undo histories
+pub fn cache_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_CACHE_HOME", ".cache")
+}
+
+fn xdg_dir(var: &str, default: &str) -> Option<PathBuf> {
+    let dir = match env::var_os(var) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(default),
+    };
+    Some(dir.join("ele"))
+}
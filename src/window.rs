@@ -0,0 +1,117 @@
+use std::mem;
+
+use crate::buffer::View;
+use crate::coord::{Pos, Size};
+
+const MIN_WIDTH: usize = 4;
+const MIN_HEIGHT: usize = 2;
+
+pub struct Window {
+    pub buffer: usize,
+    pub pos: Pos,
+    pub size: Size,
+    pub view: View,
+}
+
+impl Window {
+    pub fn new(buffer: usize) -> Self {
+        Self {
+            buffer,
+            pos: Pos::new(0, 0),
+            size: Size::new(0, 0),
+            view: Default::default(),
+        }
+    }
+
+    pub fn can_split(&self, split: Split) -> bool {
+        match split {
+            Split::Below => self.size.h >= MIN_HEIGHT * 2,
+            Split::Right => self.size.w > MIN_WIDTH * 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Split {
+    Below,
+    Right,
+}
+
+// Leaves hold window indices, which always follow the in-order traversal
+pub enum Layout {
+    Leaf(usize),
+    Split(Split, Box<Layout>, Box<Layout>),
+}
+
+impl Layout {
+    pub fn split(&mut self, index: usize, split: Split) {
+        match self {
+            Self::Leaf(i) if *i == index => {
+                let first = Box::new(Self::Leaf(index));
+                let second = Box::new(Self::Leaf(index + 1));
+                *self = Self::Split(split, first, second);
+            }
+            Self::Leaf(i) => {
+                if *i > index {
+                    *i += 1;
+                }
+            }
+            Self::Split(_, first, second) => {
+                first.split(index, split);
+                second.split(index, split);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        match self {
+            Self::Leaf(i) => {
+                if *i > index {
+                    *i -= 1;
+                }
+            }
+            Self::Split(_, first, second) => {
+                if matches!(**first, Self::Leaf(i) if i == index) {
+                    let sibling = mem::replace(&mut **second, Self::Leaf(0));
+                    *self = sibling;
+                    self.remove(index);
+                } else if matches!(**second, Self::Leaf(i) if i == index) {
+                    let sibling = mem::replace(&mut **first, Self::Leaf(0));
+                    *self = sibling;
+                    self.remove(index);
+                } else {
+                    first.remove(index);
+                    second.remove(index);
+                }
+            }
+        }
+    }
+
+    pub fn arrange(
+        &self,
+        pos: Pos,
+        size: Size,
+        windows: &mut [Window],
+        borders: &mut Vec<(Pos, usize)>,
+    ) {
+        match self {
+            Self::Leaf(i) => {
+                windows[*i].pos = pos;
+                windows[*i].size = size;
+            }
+            Self::Split(Split::Below, first, second) => {
+                let h = size.h / 2;
+                first.arrange(pos, Size::new(size.w, h), windows, borders);
+                let pos = Pos::new(pos.x, pos.y + h);
+                second.arrange(pos, Size::new(size.w, size.h - h), windows, borders);
+            }
+            Self::Split(Split::Right, first, second) => {
+                let w = (size.w - 1) / 2;
+                first.arrange(pos, Size::new(w, size.h), windows, borders);
+                borders.push((Pos::new(pos.x + w, pos.y), size.h));
+                let pos = Pos::new(pos.x + w + 1, pos.y);
+                second.arrange(pos, Size::new(size.w - w - 1, size.h), windows, borders);
+            }
+        }
+    }
+}